@@ -0,0 +1,19 @@
+#ifndef SEL4TWINKLE_ALLOC_H
+#define SEL4TWINKLE_ALLOC_H
+
+/* Generated with cbindgen from src/c_vka.rs, do not edit */
+
+#include <vka/vka.h>
+
+/**
+ * Fill in 'vka' for the Allocator at 'allocator', for C code that has been
+ * handed an allocator by a Rust component.
+ *
+ * # Safety
+ *
+ * 'allocator' must point at an Allocator (using the real kernel) that
+ * outlives 'vka', and 'vka' must be valid for writes.
+ */
+void sel4twinkle_make_vka(void *allocator, vka_t *vka);
+
+#endif /* SEL4TWINKLE_ALLOC_H */
@@ -1,18 +1,72 @@
 use super::{
-    Allocator, CapRange, Error, UntypedItem, MAX_UNTYPED_ITEMS, MAX_UNTYPED_SIZE, MIN_UNTYPED_SIZE,
-};
-use core::mem;
-use sel4_sys::{
-    api_object_seL4_UntypedObject, seL4_CPtr, seL4_CapInitThreadCNode, seL4_Untyped_Retype,
-    seL4_Word,
+    Allocator, CSpacePath, CapRange, Error, Kernel, MappedPage, ObjectType, Operation,
+    PagingStructure, ReservationEntry, Sel4Kernel, Slab, UntypedItem, UntypedNode, UntypedState,
+    VRange, VkaObject, DEFAULT_SLAB_SIZE_BITS, MAX_CSLOTS, MAX_MAPPINGS, MAX_PAGING_STRUCTURES,
+    MAX_RESERVATIONS, MAX_SLABS, MAX_UNTYPED_ITEMS, MAX_UNTYPED_NODES, MAX_UNTYPED_SIZE,
+    MAX_VSPACE_HOLES, MIN_UNTYPED_SIZE,
 };
+use arch::DEFAULT_VM_ATTRIBUTES;
+use core::cmp;
+use sel4_sys::{seL4_CPtr, seL4_CapInitThreadCNode, seL4_Word};
 
 impl Allocator {
-    /// TODO - don't need to zero, just do a normal construct
-    pub fn new() -> Allocator {
-        let alloc: Allocator = unsafe { mem::zeroed() };
+    /// Construct an allocator that invokes the real seL4 kernel.
+    ///
+    /// The allocator is too big for a small root task stack (see the
+    /// limits in lib.rs), it can be built at compile time into a static
+    /// instead:
+    ///
+    /// ```ignore
+    /// static mut ALLOCATOR: Allocator = Allocator::new();
+    /// ```
+    pub const fn new() -> Allocator {
+        Allocator::with_kernel(Sel4Kernel)
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Allocator {
+        Allocator::new()
+    }
+}
 
-        alloc
+impl<K: Kernel> Allocator<K> {
+    /// Construct an allocator that makes all of its kernel invocations
+    /// through 'kernel'.
+    pub const fn with_kernel(kernel: K) -> Allocator<K> {
+        Allocator {
+            kernel,
+            vspace_root: 0,
+            last_allocated: 0,
+            mappings: [MappedPage::unused(); MAX_MAPPINGS],
+            mappings_table: None,
+            num_mappings: 0,
+            paging_structures: [PagingStructure {
+                level: 0,
+                vaddr: 0,
+                obj: VkaObject::new(),
+                num_children: 0,
+            }; MAX_PAGING_STRUCTURES],
+            num_paging_structures: 0,
+            vspace_holes: [VRange { start: 0, size: 0 }; MAX_VSPACE_HOLES],
+            num_vspace_holes: 0,
+            reservations: [ReservationEntry {
+                range: VRange { start: 0, size: 0 },
+                rights: 0,
+                cache_attributes: DEFAULT_VM_ATTRIBUTES,
+                in_use: false,
+            }; MAX_RESERVATIONS],
+            root_cnode: 0,
+            root_cnode_depth: 0,
+            root_cnode_offset: 0,
+            cslots: CapRange { first: 0, count: 0 },
+            num_slots_used: 0,
+            cslot_bitmap: [0; MAX_CSLOTS / 32],
+            num_init_untyped_items: 0,
+            untyped_nodes: [UntypedNode::unused(); MAX_UNTYPED_NODES],
+            slabs: [Slab::unused(); MAX_SLABS],
+            slab_size_bits: DEFAULT_SLAB_SIZE_BITS,
+        }
     }
 
     /// Initialise an allocator object at 'allocator'.
@@ -42,24 +96,26 @@ impl Allocator {
         self.root_cnode_depth = root_cnode_depth as _;
         self.root_cnode_offset = root_cnode_offset as _;
         self.cslots.first = first_slot;
-        self.cslots.count = num_slots;
+        self.cslots.count = cmp::min(num_slots, MAX_CSLOTS);
         self.num_slots_used = 0;
+        for word in self.cslot_bitmap.iter_mut() {
+            *word = 0;
+        }
         self.num_init_untyped_items = 0;
 
-        // Setup all of our pools as empty
-        for i in MIN_UNTYPED_SIZE..=MAX_UNTYPED_SIZE {
-            self.untyped_items[i - MIN_UNTYPED_SIZE].first = 0;
-            self.untyped_items[i - MIN_UNTYPED_SIZE].count = 0;
+        // Setup our buddy tree as empty
+        for node in self.untyped_nodes.iter_mut() {
+            *node = UntypedNode::unused();
+        }
+
+        // No slabs yet
+        for slab in self.slabs.iter_mut() {
+            *slab = Slab::unused();
         }
 
         // Copy untyped items
-        for i in 0..items.len() {
-            self.add_root_untyped_item(
-                items[i].cap,
-                items[i].size_bits,
-                items[i].paddr,
-                items[i].is_device,
-            );
+        for item in items {
+            self.add_root_untyped_item(item.cap, item.size_bits, item.paddr, item.is_device);
         }
     }
 
@@ -80,168 +136,122 @@ impl Allocator {
         assert!(size_bits <= MAX_UNTYPED_SIZE);
         assert!(self.num_init_untyped_items < MAX_UNTYPED_ITEMS);
 
-        let next_item = self.num_init_untyped_items;
-        self.init_untyped_items[next_item].item.cap = cap;
-        self.init_untyped_items[next_item].item.size_bits = size_bits;
-        self.init_untyped_items[next_item].item.paddr = paddr;
-        self.init_untyped_items[next_item].item.is_device = is_device;
-        self.init_untyped_items[next_item].is_free = true;
-        self.num_init_untyped_items += 1;
-    }
-
-    /// Allocate an empty cslot.
-    pub fn alloc_cslot(&mut self) -> Result<seL4_CPtr, Error> {
-        // Determine whether we have any free slots
-        if (self.cslots.count - self.num_slots_used) == 0 {
-            return Err(Error::ResourceExhausted);
-        }
-
-        // Pick the first one
-        let result: seL4_CPtr = self.cslots.first as seL4_CPtr
-            + self.num_slots_used as seL4_CPtr
-            + self.root_cnode_offset;
-
-        // Record this slot as used
-        self.num_slots_used += 1;
-
-        Ok(result)
-    }
-
-    /// Free an empty cslot.
-    /// We can only free a slot if it was the last to be allocated.
-    pub fn free_cslot(&mut self, slot: seL4_CPtr) {
-        let next_slot: seL4_CPtr = self.cslots.first as seL4_CPtr
-            + self.num_slots_used as seL4_CPtr
-            + self.root_cnode_offset as seL4_CPtr;
+        let item = UntypedItem {
+            cap,
+            size_bits,
+            paddr,
+            is_device,
+        };
 
-        if next_slot == (slot + 1) {
-            self.num_slots_used -= 1;
-        }
+        // Initial memory items are the roots of the buddy tree
+        let node = self
+            .new_untyped_node(item, None)
+            .expect("no room for initial memory item");
+        self.untyped_nodes[node].state = UntypedState::Free;
+        self.num_init_untyped_items += 1;
     }
 
     /// Retype an untyped item.
+    ///
+    /// The new objects are placed in a contiguous run of free cslots.
     pub fn retype_untyped_memory(
         &mut self,
         untyped_item: seL4_CPtr,
-        item_type: seL4_Word,
+        item_type: ObjectType,
         item_size: usize,
         num_items: usize,
     ) -> Result<CapRange, Error> {
-        let mut result = CapRange { first: 0, count: 0 };
-
-        // Determine the maximum number of items we have space in our CNode for
-        let max_objects = self.cslots.count - self.num_slots_used;
-        if num_items > max_objects {
-            result.count = 0;
-            result.first = 0;
-            return Err(Error::ResourceExhausted);
-        }
+        // Find somewhere in our CNode to put the items
+        let result = self.alloc_cslot_range(num_items)?;
 
-        // Do the allocation. We expect at least one item will be created
-        let err = unsafe {
-            seL4_Untyped_Retype(
-                untyped_item,
-                item_type,
-                item_size as _,
-                seL4_CapInitThreadCNode,
-                self.root_cnode,
-                self.root_cnode_depth,
-                (self.cslots.first + self.num_slots_used) as _,
-                num_items as _,
-            )
-        };
-        if err != 0 {
-            return Err(Error::Other);
+        if let Err(e) = self.retype_untyped_memory_into(untyped_item, item_type, item_size, &result)
+        {
+            self.free_cslot_range(&result);
+            return Err(e);
         }
 
-        // Save the allocation
-        result.count = num_items;
-        result.first = self.cslots.first + self.num_slots_used + self.root_cnode_offset as usize;
-
-        // Record these slots as used
-        self.num_slots_used += num_items;
-
         Ok(result)
     }
 
-    /// Allocate untyped item of size 'size_bits' bits.
-    pub fn alloc_untyped(
+    /// As retype_untyped_memory(), into a range of cslots that's already
+    /// been allocated, one object per cslot.
+    pub(crate) fn retype_untyped_memory_into(
         &mut self,
-        size_bits: usize,
-        paddr: Option<seL4_Word>,
-        can_use_dev: bool,
-    ) -> Result<seL4_CPtr, Error> {
-        // If it is too small or too big, not much we can do
-        if size_bits < MIN_UNTYPED_SIZE {
-            return Err(Error::Other);
-        }
-        if size_bits > MAX_UNTYPED_SIZE {
-            return Err(Error::Other);
-        }
-
-        let mut pool = self.untyped_items[size_bits - MIN_UNTYPED_SIZE].clone();
-
-        // Do we have something of the correct size in one of our pools?
-        if let Ok(valid_cap) = self.range_alloc(&mut pool, 1) {
-            return Ok(valid_cap);
-        }
-
-        // Do we have something of the correct size in initial memory regions?
-        for i in 0..self.num_init_untyped_items {
-            if self.init_untyped_items[i].is_free
-                && (self.init_untyped_items[i].item.size_bits >= size_bits)
-            {
-                let mut consume = false;
-
-                if let Some(paddr) = paddr {
-                    if self.init_untyped_items[i].item.paddr == paddr {
-                        consume = true;
-                    }
-                } else {
-                    consume = true;
-                }
-
-                if !can_use_dev {
-                    if self.init_untyped_items[i].item.is_device {
-                        consume = false;
-                    }
-                }
-
-                if consume {
-                    self.init_untyped_items[i].is_free = false;
-                    return Ok(self.init_untyped_items[i].item.cap);
-                }
-            }
-        }
-
-        // Otherwise, try splitting something of a bigger size, recursively
-        let big_untyped_item = self.alloc_untyped(size_bits + 1, paddr, can_use_dev)?;
-
-        let range = self.retype_untyped_memory(
-            big_untyped_item,
-            api_object_seL4_UntypedObject,
-            size_bits,
-            2,
-        )?;
+        untyped_item: seL4_CPtr,
+        item_type: ObjectType,
+        item_size: usize,
+        range: &CapRange,
+    ) -> Result<(), Error> {
+        let dest = CSpacePath {
+            cap_ptr: range.first as _,
+            cap_depth: self.root_cnode_depth,
+            root: seL4_CapInitThreadCNode,
+            dest: self.root_cnode,
+            dest_depth: self.root_cnode_depth,
+            offset: (range.first - self.root_cnode_offset as usize) as _,
+            window: range.count as _,
+        };
 
-        assert!(range.count != 0);
-        pool = range;
+        // Do the allocation. We expect at least one item will be created
+        let err = self.kernel.untyped_retype(
+            untyped_item,
+            item_type.to_sel4(),
+            item_size as _,
+            &dest,
+            range.count as _,
+        );
+        Error::check(Operation::Retype, err)
+    }
+}
 
-        // Allocate and return out of our split
-        self.range_alloc(&mut pool, 1)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sel4_sys::*;
+    use sim;
+    use KernelError;
+
+    // Built at compile time, so it never has to fit on a stack
+    static ALLOCATOR: Allocator = Allocator::new();
+
+    #[test]
+    fn new_allocator_can_be_a_static() {
+        assert_eq!(ALLOCATOR.num_slots_used, 0);
+        assert_eq!(ALLOCATOR.num_mappings, 0);
+        assert_eq!(ALLOCATOR.slab_size_bits, DEFAULT_SLAB_SIZE_BITS);
     }
 
-    /// Allocate 'count' items out of the given range.
-    fn range_alloc(&mut self, range: &mut CapRange, count: usize) -> Result<seL4_CPtr, Error> {
-        // If there are not enough items in the range, abort
-        if range.count < count {
-            return Err(Error::ResourceExhausted);
+    #[test]
+    fn retype_untyped_memory_fills_consecutive_slots() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let ut = alloc.alloc_untyped(12, None, false).unwrap();
+
+        let range = alloc
+            .retype_untyped_memory(ut, ObjectType::Endpoint, 0, 4)
+            .unwrap();
+        assert_eq!(range.count, 4);
+        for i in 0..4 {
+            let slot = (range.first + i) as seL4_CPtr;
+            assert_eq!(
+                alloc.kernel.cap_type(slot),
+                Some(api_object_seL4_EndpointObject)
+            );
         }
+    }
 
-        // Allocate from the range
-        assert!(range.first != 0);
-        range.count -= count;
-
-        return Ok((range.first + range.count) as _);
+    #[test]
+    fn retype_untyped_memory_fails_when_untyped_is_too_small() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let ut = alloc.alloc_untyped(12, None, false).unwrap();
+        let caps_before = alloc.kernel.num_caps();
+
+        assert_eq!(
+            alloc.retype_untyped_memory(ut, ObjectType::Tcb, 0, 16),
+            Err(Error::Kernel(
+                Operation::Retype,
+                KernelError::NotEnoughMemory
+            ))
+        );
+        assert_eq!(alloc.kernel.num_caps(), caps_before);
     }
 }
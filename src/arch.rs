@@ -0,0 +1,203 @@
+/// Architecture specific parts of the vspace, selected with the cargo
+/// features.
+///
+/// See https://github.com/seL4/seL4_libs/tree/master/libsel4vspace/src/sel4_arch
+use super::ObjectType;
+use sel4_sys::*;
+
+/// A paging structure that sits between the vspace root and the frames,
+/// allocated on demand when a page is mapped where there isn't one yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PagingLevel {
+    pub obj_type: ObjectType,
+    /// Bits of virtual address space a single structure at this level covers
+    pub vaddr_bits: usize,
+}
+
+#[cfg(any(feature = "aarch32", feature = "aarch64"))]
+pub type VmAttributes = seL4_ARM_VMAttributes;
+
+#[cfg(any(feature = "aarch32", feature = "aarch64"))]
+pub const DEFAULT_VM_ATTRIBUTES: VmAttributes = seL4_ARM_VMAttributes_seL4_ARM_Default_VMAttributes;
+
+/// Uncached, used for memory mapped devices
+#[cfg(any(feature = "aarch32", feature = "aarch64"))]
+pub const DEVICE_VM_ATTRIBUTES: VmAttributes = 0;
+
+#[cfg(feature = "x86_64")]
+pub type VmAttributes = seL4_X86_VMAttributes;
+
+#[cfg(feature = "x86_64")]
+pub const DEFAULT_VM_ATTRIBUTES: VmAttributes = seL4_X86_VMAttributes_seL4_X86_Default_VMAttributes;
+
+/// Uncached, used for memory mapped devices
+#[cfg(feature = "x86_64")]
+pub const DEVICE_VM_ATTRIBUTES: VmAttributes = seL4_X86_VMAttributes_seL4_X86_Uncacheable;
+
+#[cfg(feature = "riscv64")]
+pub type VmAttributes = seL4_RISCV_VMAttributes;
+
+#[cfg(feature = "riscv64")]
+pub const DEFAULT_VM_ATTRIBUTES: VmAttributes =
+    seL4_RISCV_VMAttributes_seL4_RISCV_Default_VMAttributes;
+
+/// RISC-V page tables don't carry cacheability, the platform's physical
+/// memory attributes decide it for device memory
+#[cfg(feature = "riscv64")]
+pub const DEVICE_VM_ATTRIBUTES: VmAttributes =
+    seL4_RISCV_VMAttributes_seL4_RISCV_Default_VMAttributes;
+
+/// Frame sizes (in bits) pages can be mapped with, largest first.
+#[cfg(feature = "aarch32")]
+pub const FRAME_SIZES: &[usize] = &[
+    seL4_SuperSectionBits as _,
+    seL4_SectionBits as _,
+    seL4_LargePageBits as _,
+    seL4_PageBits as _,
+];
+
+#[cfg(any(feature = "aarch64", feature = "x86_64", feature = "riscv64"))]
+pub const FRAME_SIZES: &[usize] = &[
+    seL4_HugePageBits as _,
+    seL4_LargePageBits as _,
+    seL4_PageBits as _,
+];
+
+/// The page directory is the vspace root, each of its entries maps a page
+/// table covering 1M.
+#[cfg(feature = "aarch32")]
+pub const PAGING_LEVELS: &[PagingLevel] = &[PagingLevel {
+    obj_type: ObjectType::PageTable,
+    vaddr_bits: 20,
+}];
+
+/// The page global directory is the vspace root, with a 4K granule and
+/// 48 bit virtual addresses below it.
+#[cfg(all(feature = "aarch64", not(feature = "hyp")))]
+pub const PAGING_LEVELS: &[PagingLevel] = &[
+    PagingLevel {
+        obj_type: ObjectType::PageUpperDirectory,
+        vaddr_bits: 39,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageDirectory,
+        vaddr_bits: 30,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 21,
+    },
+];
+
+/// With hypervisor support the vspace is a stage 2 translation of a 40 bit
+/// IPA, the page upper directory (of 1024 entries) is its root.
+#[cfg(all(feature = "aarch64", feature = "hyp"))]
+pub const PAGING_LEVELS: &[PagingLevel] = &[
+    PagingLevel {
+        obj_type: ObjectType::PageDirectory,
+        vaddr_bits: 30,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 21,
+    },
+];
+
+/// The PML4 is the vspace root, with 48 bit virtual addresses below it.
+#[cfg(feature = "x86_64")]
+pub const PAGING_LEVELS: &[PagingLevel] = &[
+    PagingLevel {
+        obj_type: ObjectType::Pdpt,
+        vaddr_bits: 39,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageDirectory,
+        vaddr_bits: 30,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 21,
+    },
+];
+
+/// Every level is a page table, the root one being the vspace root, with
+/// 39 bit (Sv39) virtual addresses.
+#[cfg(all(feature = "riscv64", not(feature = "sv48")))]
+pub const PAGING_LEVELS: &[PagingLevel] = &[
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 30,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 21,
+    },
+];
+
+/// As Sv39 with one more level of page table, 48 bit virtual addresses.
+#[cfg(all(feature = "riscv64", feature = "sv48"))]
+pub const PAGING_LEVELS: &[PagingLevel] = &[
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 39,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 30,
+    },
+    PagingLevel {
+        obj_type: ObjectType::PageTable,
+        vaddr_bits: 21,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size (in bits) of a paging structure entry
+    #[cfg(feature = "aarch32")]
+    const ENTRY_BITS: usize = 2;
+    #[cfg(any(feature = "aarch64", feature = "x86_64", feature = "riscv64"))]
+    const ENTRY_BITS: usize = 3;
+
+    /// Each level's entries cover exactly what one structure of the next
+    /// level down covers, ending at the page size.
+    #[test]
+    fn paging_levels_cover_the_address_space_below_them() {
+        let mut covered = seL4_PageBits as usize;
+
+        for level in PAGING_LEVELS.iter().rev() {
+            let size_bits = level.obj_type.size_bits(0).unwrap();
+            covered += size_bits - ENTRY_BITS;
+            assert_eq!(level.vaddr_bits, covered, "{:?}", level.obj_type);
+        }
+    }
+
+    /// The vspace root's entries each cover one structure of the first
+    /// level, the root itself covering the whole address space.
+    #[test]
+    #[cfg(feature = "aarch64")]
+    fn aarch64_vspace_root_covers_the_address_space() {
+        #[cfg(not(feature = "hyp"))]
+        let (root, vaddr_bits) = (ObjectType::PageGlobalDirectory, 48);
+        #[cfg(feature = "hyp")]
+        let (root, vaddr_bits) = (ObjectType::PageUpperDirectory, 40);
+
+        let root_bits = root.size_bits(0).unwrap();
+        assert_eq!(
+            PAGING_LEVELS[0].vaddr_bits + root_bits - ENTRY_BITS,
+            vaddr_bits
+        );
+        assert!(PAGING_LEVELS.iter().all(|level| level.obj_type != root));
+    }
+
+    #[test]
+    fn frame_sizes_are_frames_largest_first() {
+        for bits in FRAME_SIZES.iter() {
+            assert!(ObjectType::frame(*bits).is_ok(), "{}", bits);
+        }
+        assert!(FRAME_SIZES.windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(FRAME_SIZES.last(), Some(&(seL4_PageBits as usize)));
+    }
+}
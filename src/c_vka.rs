@@ -0,0 +1,340 @@
+/// C ABI shim, so C libraries (libsel4utils, libsel4platsupport, ...) can
+/// allocate through a Vka via libsel4vka's vka_t.
+///
+/// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/vka.h
+///
+/// The C declarations are in include/sel4twinkle_alloc.h, generated with
+/// `cbindgen --config cbindgen.toml --output include/sel4twinkle_alloc.h`.
+use super::{Allocator, CSpacePath, Error, ObjectType, Vka, VKA_NO_PADDR};
+use core::ffi::{c_int, c_void};
+use core::ptr;
+use sel4_sys::{seL4_CPtr, seL4_Word};
+
+/// libsel4vka's vka_t, a Vka behind C function pointers.
+///
+/// 'data' points at the Vka, it must outlive the vka_t and not move.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct vka_t {
+    pub data: *mut c_void,
+    pub cspace_alloc: Option<unsafe extern "C" fn(*mut c_void, *mut seL4_CPtr) -> c_int>,
+    pub cspace_make_path: Option<unsafe extern "C" fn(*mut c_void, seL4_CPtr, *mut CSpacePath)>,
+    pub utspace_alloc: Option<
+        unsafe extern "C" fn(
+            *mut c_void,
+            *const CSpacePath,
+            seL4_Word,
+            seL4_Word,
+            *mut seL4_Word,
+        ) -> c_int,
+    >,
+    pub utspace_alloc_maybe_device: Option<
+        unsafe extern "C" fn(
+            *mut c_void,
+            *const CSpacePath,
+            seL4_Word,
+            seL4_Word,
+            bool,
+            *mut seL4_Word,
+        ) -> c_int,
+    >,
+    pub utspace_alloc_at: Option<
+        unsafe extern "C" fn(
+            *mut c_void,
+            *const CSpacePath,
+            seL4_Word,
+            seL4_Word,
+            usize,
+            *mut seL4_Word,
+        ) -> c_int,
+    >,
+    pub cspace_free: Option<unsafe extern "C" fn(*mut c_void, seL4_CPtr)>,
+    pub utspace_free: Option<unsafe extern "C" fn(*mut c_void, seL4_Word, seL4_Word, seL4_Word)>,
+    pub utspace_paddr:
+        Option<unsafe extern "C" fn(*mut c_void, seL4_Word, seL4_Word, seL4_Word) -> usize>,
+}
+
+/// Fill in a vka_t that calls into 'vka'.
+pub fn make_c_vka<V: Vka>(vka: &mut V) -> vka_t {
+    vka_t {
+        data: vka as *mut V as *mut c_void,
+        cspace_alloc: Some(cspace_alloc::<V>),
+        cspace_make_path: Some(cspace_make_path::<V>),
+        utspace_alloc: Some(utspace_alloc::<V>),
+        utspace_alloc_maybe_device: Some(utspace_alloc_maybe_device::<V>),
+        utspace_alloc_at: Some(utspace_alloc_at::<V>),
+        cspace_free: Some(cspace_free::<V>),
+        utspace_free: Some(utspace_free::<V>),
+        utspace_paddr: Some(utspace_paddr::<V>),
+    }
+}
+
+/// Fill in 'vka' for the Allocator at 'allocator', for C code that has been
+/// handed an allocator by a Rust component.
+///
+/// # Safety
+///
+/// 'allocator' must point at an Allocator (using the real kernel) that
+/// outlives 'vka', and 'vka' must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sel4twinkle_make_vka(allocator: *mut c_void, vka: *mut vka_t) {
+    ptr::write(vka, make_c_vka(&mut *(allocator as *mut Allocator)));
+}
+
+/// The object type and 'size_bits' for a libsel4vka object type and the
+/// memory size (in bits) it's passed, see ObjectType::size_bits_for_memory()
+fn object_type(item_type: seL4_Word, memory_bits: seL4_Word) -> Option<(ObjectType, usize)> {
+    let item_type = ObjectType::from_sel4(item_type)?;
+    let size_bits = item_type.size_bits_for_memory(memory_bits as _).ok()?;
+
+    Some((item_type, size_bits))
+}
+
+/// 0 on success, non-zero otherwise, as libsel4vka expects
+fn status(res: Result<(), Error>) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+unsafe fn utspace_alloc_to<V: Vka>(
+    data: *mut c_void,
+    dest: *const CSpacePath,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+    paddr: Option<seL4_Word>,
+    can_use_dev: bool,
+    res: *mut seL4_Word,
+) -> c_int {
+    let vka = &mut *(data as *mut V);
+    let dest = &*dest;
+
+    let (item_type, size_bits) = match object_type(item_type, size_bits) {
+        Some(object) => object,
+        None => return -1,
+    };
+
+    let ut = match paddr {
+        Some(paddr) => vka.vka_utspace_alloc_at(dest, item_type, size_bits, paddr, can_use_dev),
+        None => vka.vka_utspace_alloc_maybe_device(dest, item_type, size_bits, can_use_dev),
+    };
+
+    status(ut.map(|ut| *res = ut))
+}
+
+unsafe extern "C" fn cspace_alloc<V: Vka>(data: *mut c_void, res: *mut seL4_CPtr) -> c_int {
+    let vka = &mut *(data as *mut V);
+
+    status(vka.vka_cspace_alloc().map(|slot| *res = slot))
+}
+
+unsafe extern "C" fn cspace_make_path<V: Vka>(
+    data: *mut c_void,
+    slot: seL4_CPtr,
+    res: *mut CSpacePath,
+) {
+    let vka = &*(data as *const V);
+
+    ptr::write(res, vka.vka_cspace_make_path(slot));
+}
+
+unsafe extern "C" fn utspace_alloc<V: Vka>(
+    data: *mut c_void,
+    dest: *const CSpacePath,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+    res: *mut seL4_Word,
+) -> c_int {
+    utspace_alloc_to::<V>(data, dest, item_type, size_bits, None, false, res)
+}
+
+unsafe extern "C" fn utspace_alloc_maybe_device<V: Vka>(
+    data: *mut c_void,
+    dest: *const CSpacePath,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+    can_use_dev: bool,
+    res: *mut seL4_Word,
+) -> c_int {
+    utspace_alloc_to::<V>(data, dest, item_type, size_bits, None, can_use_dev, res)
+}
+
+unsafe extern "C" fn utspace_alloc_at<V: Vka>(
+    data: *mut c_void,
+    dest: *const CSpacePath,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+    paddr: usize,
+    res: *mut seL4_Word,
+) -> c_int {
+    utspace_alloc_to::<V>(
+        data,
+        dest,
+        item_type,
+        size_bits,
+        Some(paddr as _),
+        true,
+        res,
+    )
+}
+
+unsafe extern "C" fn cspace_free<V: Vka>(data: *mut c_void, slot: seL4_CPtr) {
+    let vka = &mut *(data as *mut V);
+
+    vka.vka_cspace_free(slot)
+}
+
+unsafe extern "C" fn utspace_free<V: Vka>(
+    data: *mut c_void,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+    target: seL4_Word,
+) {
+    let vka = &mut *(data as *mut V);
+
+    // Nothing to report a failure to
+    if let Some((item_type, size_bits)) = object_type(item_type, size_bits) {
+        let _ = vka.vka_utspace_free(item_type, size_bits, target);
+    }
+}
+
+unsafe extern "C" fn utspace_paddr<V: Vka>(
+    data: *mut c_void,
+    target: seL4_Word,
+    item_type: seL4_Word,
+    size_bits: seL4_Word,
+) -> usize {
+    let vka = &*(data as *const V);
+
+    let (item_type, size_bits) = match object_type(item_type, size_bits) {
+        Some(object) => object,
+        None => return VKA_NO_PADDR as _,
+    };
+
+    vka.vka_utspace_paddr(target, item_type, size_bits)
+        .unwrap_or(VKA_NO_PADDR) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+    use sel4_sys::*;
+    use sim;
+
+    #[test]
+    fn c_vka_allocates_through_the_vka() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let vka = make_c_vka(&mut alloc);
+
+        unsafe {
+            let mut slot: seL4_CPtr = 0;
+            assert_eq!((vka.cspace_alloc.unwrap())(vka.data, &mut slot), 0);
+
+            let mut path: CSpacePath = mem::zeroed();
+            (vka.cspace_make_path.unwrap())(vka.data, slot, &mut path);
+            assert_eq!(path.cap_ptr, slot);
+
+            let mut ut: seL4_Word = 0;
+            let frame_type = ObjectType::SmallPage.to_sel4();
+            let size_bits = seL4_PageBits as seL4_Word;
+            assert_eq!(
+                (vka.utspace_alloc_at.unwrap())(
+                    vka.data,
+                    &path,
+                    frame_type,
+                    size_bits,
+                    0x1000_0000,
+                    &mut ut
+                ),
+                0
+            );
+            assert_eq!(
+                (vka.utspace_paddr.unwrap())(vka.data, ut, frame_type, size_bits),
+                0x1000_0000
+            );
+
+            // Unknown object types are refused
+            assert_ne!(
+                (vka.utspace_alloc.unwrap())(vka.data, &path, !0, 0, &mut ut),
+                0
+            );
+
+            (vka.utspace_free.unwrap())(vka.data, frame_type, size_bits, ut);
+            (vka.cspace_free.unwrap())(vka.data, slot);
+        }
+
+        assert_eq!(alloc.num_slots_used, 0);
+        assert!(alloc.alloc_untyped(12, None, false).is_ok());
+    }
+
+    #[test]
+    fn c_vka_sizes_are_the_memory_size() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let vka = make_c_vka(&mut alloc);
+        let cnode_type = ObjectType::CapTable.to_sel4();
+        let memory_bits = ObjectType::CapTable.size_bits(4).unwrap() as seL4_Word;
+
+        let slot = unsafe {
+            let mut slot: seL4_CPtr = 0;
+            assert_eq!((vka.cspace_alloc.unwrap())(vka.data, &mut slot), 0);
+            let mut path: CSpacePath = mem::zeroed();
+            (vka.cspace_make_path.unwrap())(vka.data, slot, &mut path);
+
+            // As vka_alloc_cnode_object() passes it
+            let mut ut: seL4_Word = 0;
+            assert_eq!(
+                (vka.utspace_alloc.unwrap())(vka.data, &path, cnode_type, memory_bits, &mut ut),
+                0
+            );
+            assert_eq!(
+                (vka.utspace_paddr.unwrap())(vka.data, ut, cnode_type, memory_bits),
+                0x1000_0000
+            );
+
+            // Not a size a CNode can be
+            let mut other: seL4_Word = 0;
+            assert_ne!(
+                (vka.utspace_alloc.unwrap())(vka.data, &path, cnode_type, 2, &mut other),
+                0
+            );
+
+            slot
+        };
+
+        // A CNode of 2^4 slots
+        assert_eq!(alloc.kernel.cap_type(slot), Some(cnode_type));
+        assert_eq!(alloc.kernel.cap_size_bits(slot), Some(memory_bits as _));
+    }
+
+    #[test]
+    fn c_vka_uses_device_memory_only_when_allowed() {
+        let mut alloc = sim::allocator(&[(0x0209_8000, 12, true)]);
+        let vka = make_c_vka(&mut alloc);
+
+        unsafe {
+            let mut slot: seL4_CPtr = 0;
+            assert_eq!((vka.cspace_alloc.unwrap())(vka.data, &mut slot), 0);
+            let mut path: CSpacePath = mem::zeroed();
+            (vka.cspace_make_path.unwrap())(vka.data, slot, &mut path);
+
+            let mut ut: seL4_Word = 0;
+            let frame_type = ObjectType::SmallPage.to_sel4();
+            let size_bits = seL4_PageBits as seL4_Word;
+            let alloc_maybe_device = vka.utspace_alloc_maybe_device.unwrap();
+            assert_ne!(
+                alloc_maybe_device(vka.data, &path, frame_type, size_bits, false, &mut ut),
+                0
+            );
+            assert_eq!(
+                alloc_maybe_device(vka.data, &path, frame_type, size_bits, true, &mut ut),
+                0
+            );
+            assert_eq!(
+                (vka.utspace_paddr.unwrap())(vka.data, ut, frame_type, size_bits),
+                0x0209_8000
+            );
+        }
+    }
+}
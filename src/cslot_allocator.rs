@@ -0,0 +1,186 @@
+/// Free slot tracking for the CNode we allocate from.
+///
+/// Each slot in the 'cslots' range has a bit in 'cslot_bitmap', so slots
+/// can be freed in any order and contiguous runs of slots can be handed
+/// out for retyping several objects at once.
+use super::{Allocator, CapRange, Error, Kernel};
+use sel4_sys::seL4_CPtr;
+
+impl CapRange {
+    /// The first cslot in the range.
+    pub fn first(&self) -> seL4_CPtr {
+        self.first as _
+    }
+
+    /// Number of cslots in the range.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The i'th cslot in the range, None if it's out of range.
+    pub fn get(&self, i: usize) -> Option<seL4_CPtr> {
+        if i < self.count {
+            Some((self.first + i) as _)
+        } else {
+            None
+        }
+    }
+}
+
+impl<K: Kernel> Allocator<K> {
+    /// Allocate an empty cslot.
+    pub fn alloc_cslot(&mut self) -> Result<seL4_CPtr, Error> {
+        let range = self.alloc_cslot_range(1)?;
+
+        Ok(range.first as _)
+    }
+
+    /// Free an empty cslot.
+    ///
+    /// Slots we don't manage, or that are already free, are ignored.
+    pub fn free_cslot(&mut self, slot: seL4_CPtr) {
+        let first = self.cslots.first as seL4_CPtr + self.root_cnode_offset;
+
+        if (slot < first) || (slot >= (first + self.cslots.count as seL4_CPtr)) {
+            return;
+        }
+
+        let index = (slot - first) as usize;
+        if self.cslot_is_used(index) {
+            self.cslot_bitmap[index / 32] &= !(1 << (index % 32));
+            self.num_slots_used -= 1;
+        }
+    }
+
+    /// Allocate 'count' contiguous empty cslots.
+    pub fn alloc_cslot_range(&mut self, count: usize) -> Result<CapRange, Error> {
+        // Determine whether we have enough free slots at all
+        if (count == 0) || ((self.cslots.count - self.num_slots_used) < count) {
+            return Err(Error::SlotsExhausted);
+        }
+
+        // First fit
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut index = 0;
+        while index < self.cslots.count {
+            // Skip over words that are fully used
+            if (index % 32 == 0) && (self.cslot_bitmap[index / 32] == !0) {
+                index += 32;
+                run_len = 0;
+                continue;
+            }
+
+            if self.cslot_is_used(index) {
+                run_len = 0;
+            } else {
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+
+                if run_len == count {
+                    break;
+                }
+            }
+
+            index += 1;
+        }
+
+        if run_len != count {
+            return Err(Error::SlotsExhausted);
+        }
+
+        // Record these slots as used
+        for i in run_start..(run_start + count) {
+            self.cslot_bitmap[i / 32] |= 1 << (i % 32);
+        }
+        self.num_slots_used += count;
+
+        Ok(CapRange {
+            first: self.cslots.first + run_start + self.root_cnode_offset as usize,
+            count,
+        })
+    }
+
+    /// Free a range of empty cslots.
+    pub fn free_cslot_range(&mut self, range: &CapRange) {
+        for i in 0..range.count {
+            self.free_cslot((range.first + i) as _);
+        }
+    }
+
+    fn cslot_is_used(&self, index: usize) -> bool {
+        (self.cslot_bitmap[index / 32] & (1 << (index % 32))) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sim::{SimKernel, SIM_CNODE, SIM_FIRST_FREE_SLOT};
+    use std::vec::Vec;
+
+    fn allocator(num_slots: usize) -> Allocator<SimKernel> {
+        let mut alloc = Allocator::with_kernel(SimKernel::new());
+        alloc.create(SIM_CNODE, 32, 0, SIM_FIRST_FREE_SLOT as _, num_slots, &[]);
+        alloc
+    }
+
+    #[test]
+    fn cslots_are_handed_out_in_order_until_exhausted() {
+        let mut alloc = allocator(2);
+
+        assert_eq!(alloc.alloc_cslot(), Ok(SIM_FIRST_FREE_SLOT));
+        assert_eq!(alloc.alloc_cslot(), Ok(SIM_FIRST_FREE_SLOT + 1));
+        assert_eq!(alloc.alloc_cslot(), Err(Error::SlotsExhausted));
+
+        alloc.free_cslot(SIM_FIRST_FREE_SLOT + 1);
+        assert_eq!(alloc.alloc_cslot(), Ok(SIM_FIRST_FREE_SLOT + 1));
+    }
+
+    #[test]
+    fn cslots_can_be_freed_in_any_order() {
+        let mut alloc = allocator(8);
+        let slots: Vec<seL4_CPtr> = (0..8).map(|_| alloc.alloc_cslot().unwrap()).collect();
+
+        alloc.free_cslot(slots[2]);
+        alloc.free_cslot(slots[5]);
+        assert_eq!(alloc.alloc_cslot(), Ok(slots[2]));
+        assert_eq!(alloc.alloc_cslot(), Ok(slots[5]));
+        assert!(alloc.alloc_cslot().is_err());
+    }
+
+    #[test]
+    fn cslot_ranges_are_contiguous() {
+        let mut alloc = allocator(64);
+        let slots: Vec<seL4_CPtr> = (0..40).map(|_| alloc.alloc_cslot().unwrap()).collect();
+
+        // Leave a hole too small for the range
+        alloc.free_cslot(slots[3]);
+        alloc.free_cslot(slots[4]);
+
+        let range = alloc.alloc_cslot_range(4).unwrap();
+        assert_eq!(range.first as seL4_CPtr, SIM_FIRST_FREE_SLOT + 40);
+        assert_eq!(range.count, 4);
+
+        // Now make the hole big enough, spanning a bitmap word boundary
+        for slot in &slots[30..36] {
+            alloc.free_cslot(*slot);
+        }
+        let range = alloc.alloc_cslot_range(6).unwrap();
+        assert_eq!(range.first as seL4_CPtr, slots[30]);
+
+        assert!(alloc.alloc_cslot_range(64).is_err());
+    }
+
+    #[test]
+    fn freeing_unknown_cslots_is_ignored() {
+        let mut alloc = allocator(2);
+
+        alloc.free_cslot(SIM_FIRST_FREE_SLOT);
+        alloc.free_cslot(SIM_FIRST_FREE_SLOT - 1);
+        alloc.free_cslot(SIM_FIRST_FREE_SLOT + 2);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+}
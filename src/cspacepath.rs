@@ -1,6 +1,8 @@
 /// See https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/cspacepath_t.h
 use sel4_sys::{seL4_CNode, seL4_CPtr, seL4_Word};
 
+/// Laid out as libsel4vka's cspacepath_t
+#[repr(C)]
 #[derive(Clone, Debug)]
 pub struct CSpacePath {
     pub cap_ptr: seL4_CPtr,
@@ -0,0 +1,175 @@
+/// See https://github.com/seL4/seL4/blob/master/libsel4/include/sel4/errors.h
+use core::fmt;
+use sel4_sys::*;
+
+/// An seL4_Error returned by a kernel invocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelError {
+    InvalidArgument,
+    InvalidCapability,
+    IllegalOperation,
+    RangeError,
+    AlignmentError,
+    FailedLookup,
+    TruncatedMessage,
+    DeleteFirst,
+    RevokeFirst,
+    NotEnoughMemory,
+    /// Not an error code this crate knows about
+    Unknown(seL4_Error),
+}
+
+/// The kernel invocation that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Retype,
+    PageMap,
+    PageUnmap,
+    /// Mapping any of the intermediate paging structures
+    PageTableMap,
+    /// Unmapping any of the intermediate paging structures
+    PageTableUnmap,
+    CNodeDelete,
+    CNodeRevoke,
+    #[cfg(feature = "mcs")]
+    SchedControlConfigure,
+    #[cfg(feature = "x86_64")]
+    IoPortIssue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No untyped memory available to satisfy the request
+    ResourceExhausted,
+    /// No free cslots left in our CNode
+    SlotsExhausted,
+    /// No room left to track any more untyped items
+    UntypedNodesExhausted,
+    /// The requested size (in bits) isn't supported
+    SizeOutOfRange(usize),
+    /// The untyped cap wasn't handed out by this allocator
+    UnknownUntyped(seL4_CPtr),
+    /// No room left to track any more object slabs
+    SlabsExhausted,
+    /// The cap wasn't handed out by slab_alloc(), or was already freed
+    UnknownSlabObject(seL4_CPtr),
+    /// No room left to track any more mapped pages
+    MappingsExhausted,
+    /// No page of the expected size was mapped at the vaddr by the vspace
+    /// functions
+    NotMapped(seL4_Word),
+    /// No room left to track any more paging structures
+    PagingStructuresExhausted,
+    /// Pages are still mapped at the vaddr
+    StillMapped(seL4_Word),
+    /// The vaddr is already mapped or reserved
+    VaddrInUse(seL4_Word),
+    /// The vaddr isn't aligned to the page size
+    Misaligned(seL4_Word),
+    /// No room left to track any more vspace reservations
+    ReservationsExhausted,
+    /// No room left to track any more unused ranges of the vspace
+    VspaceHolesExhausted,
+    /// The reservation wasn't handed out by this allocator, or was freed
+    UnknownReservation,
+    /// The vaddr is outside of the reservation
+    NotReserved(seL4_Word),
+    /// A kernel invocation failed
+    Kernel(Operation, KernelError),
+}
+
+impl KernelError {
+    /// Convert an seL4_Error, None if it is seL4_NoError.
+    pub fn from_sel4(err: seL4_Error) -> Option<KernelError> {
+        #[allow(non_upper_case_globals)]
+        let kerr = match err {
+            seL4_Error_seL4_NoError => return None,
+            seL4_Error_seL4_InvalidArgument => KernelError::InvalidArgument,
+            seL4_Error_seL4_InvalidCapability => KernelError::InvalidCapability,
+            seL4_Error_seL4_IllegalOperation => KernelError::IllegalOperation,
+            seL4_Error_seL4_RangeError => KernelError::RangeError,
+            seL4_Error_seL4_AlignmentError => KernelError::AlignmentError,
+            seL4_Error_seL4_FailedLookup => KernelError::FailedLookup,
+            seL4_Error_seL4_TruncatedMessage => KernelError::TruncatedMessage,
+            seL4_Error_seL4_DeleteFirst => KernelError::DeleteFirst,
+            seL4_Error_seL4_RevokeFirst => KernelError::RevokeFirst,
+            seL4_Error_seL4_NotEnoughMemory => KernelError::NotEnoughMemory,
+            _ => KernelError::Unknown(err),
+        };
+
+        Some(kerr)
+    }
+}
+
+impl Error {
+    /// Check the seL4_Error returned by the kernel for 'op'.
+    pub(crate) fn check(op: Operation, err: seL4_Error) -> Result<(), Error> {
+        match KernelError::from_sel4(err) {
+            None => Ok(()),
+            Some(kerr) => Err(Error::Kernel(op, kerr)),
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KernelError::InvalidArgument => write!(f, "seL4_InvalidArgument"),
+            KernelError::InvalidCapability => write!(f, "seL4_InvalidCapability"),
+            KernelError::IllegalOperation => write!(f, "seL4_IllegalOperation"),
+            KernelError::RangeError => write!(f, "seL4_RangeError"),
+            KernelError::AlignmentError => write!(f, "seL4_AlignmentError"),
+            KernelError::FailedLookup => write!(f, "seL4_FailedLookup"),
+            KernelError::TruncatedMessage => write!(f, "seL4_TruncatedMessage"),
+            KernelError::DeleteFirst => write!(f, "seL4_DeleteFirst"),
+            KernelError::RevokeFirst => write!(f, "seL4_RevokeFirst"),
+            KernelError::NotEnoughMemory => write!(f, "seL4_NotEnoughMemory"),
+            KernelError::Unknown(err) => write!(f, "unknown seL4_Error {}", err),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Retype => write!(f, "seL4_Untyped_Retype"),
+            Operation::PageMap => write!(f, "page map"),
+            Operation::PageUnmap => write!(f, "page unmap"),
+            Operation::PageTableMap => write!(f, "page table map"),
+            Operation::PageTableUnmap => write!(f, "page table unmap"),
+            Operation::CNodeDelete => write!(f, "seL4_CNode_Delete"),
+            Operation::CNodeRevoke => write!(f, "seL4_CNode_Revoke"),
+            #[cfg(feature = "mcs")]
+            Operation::SchedControlConfigure => write!(f, "seL4_SchedControl_Configure"),
+            #[cfg(feature = "x86_64")]
+            Operation::IoPortIssue => write!(f, "seL4_X86_IOPortControl_Issue"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ResourceExhausted => write!(f, "out of untyped memory"),
+            Error::SlotsExhausted => write!(f, "out of cslots"),
+            Error::UntypedNodesExhausted => write!(f, "too many untyped items to track"),
+            Error::SizeOutOfRange(size_bits) => {
+                write!(f, "size of {} bits is out of range", size_bits)
+            }
+            Error::UnknownUntyped(cap) => write!(f, "untyped cap {} is not allocated", cap),
+            Error::SlabsExhausted => write!(f, "too many object slabs"),
+            Error::UnknownSlabObject(cap) => write!(f, "cap {} is not a slab object", cap),
+            Error::MappingsExhausted => write!(f, "too many mapped pages to track"),
+            Error::NotMapped(vaddr) => write!(f, "no page is mapped at {:#x}", vaddr),
+            Error::PagingStructuresExhausted => write!(f, "too many paging structures to track"),
+            Error::StillMapped(vaddr) => write!(f, "a page is still mapped at {:#x}", vaddr),
+            Error::VaddrInUse(vaddr) => write!(f, "vaddr {:#x} is already in use", vaddr),
+            Error::Misaligned(vaddr) => write!(f, "vaddr {:#x} is misaligned", vaddr),
+            Error::ReservationsExhausted => write!(f, "too many vspace reservations"),
+            Error::VspaceHolesExhausted => write!(f, "too many unused vspace ranges to track"),
+            Error::UnknownReservation => write!(f, "unknown vspace reservation"),
+            Error::NotReserved(vaddr) => write!(f, "vaddr {:#x} is outside the reservation", vaddr),
+            Error::Kernel(op, err) => write!(f, "{} failed with {}", op, err),
+        }
+    }
+}
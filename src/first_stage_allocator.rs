@@ -1,7 +1,7 @@
-use super::Allocator;
+use super::{Allocator, Kernel};
 use sel4_sys::{seL4_BootInfo, seL4_CapInitThreadCNode, seL4_WordBits};
 
-impl Allocator {
+impl<K: Kernel> Allocator<K> {
     /// Create an object allocator managing the root CNode's free slots.
     pub fn bootstrap(&mut self, bootinfo: &'static seL4_BootInfo) {
         // Create the allocator
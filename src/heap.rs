@@ -0,0 +1,402 @@
+/// A heap that can be used as the #[global_allocator].
+///
+/// Memory comes from vspace_new_pages() as needed, like morecore in a C
+/// libc, and is managed with an address ordered free list that coalesces
+/// neighbouring blocks on free.
+///
+/// GlobalAlloc only gets '&self', so the heap state sits behind a spinlock.
+/// The lock also guards the Allocator the heap grows from, use
+/// Heap::with_allocator() to get at it once it has been handed to the heap.
+///
+/// ```ignore
+/// #[global_allocator]
+/// static HEAP: Heap = Heap::new();
+///
+/// unsafe { HEAP.init(allocator) };
+/// ```
+use super::{Allocator, Kernel, VSPACE_START};
+use arch::DEFAULT_VM_ATTRIBUTES;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use sel4_sys::*;
+
+/// Smallest amount of memory the heap grows by
+pub const MIN_HEAP_GROWTH: usize = 64 * 1024;
+
+/// Where the heap gets more memory from when it runs out.
+pub trait MoreCore {
+    /// Map at least 'size' bytes of new memory, returning its address and
+    /// size. Both are a multiple of the page size.
+    fn more_core(&mut self, size: usize) -> Option<(usize, usize)>;
+}
+
+impl<K: Kernel> MoreCore for Allocator<K> {
+    fn more_core(&mut self, size: usize) -> Option<(usize, usize)> {
+        let page_size = 1 << seL4_PageBits;
+        let num_pages = (size + page_size - 1) / page_size;
+
+        let vaddr = self
+            .vspace_new_pages(
+                num_pages,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .ok()?;
+        debug_assert!(vaddr >= VSPACE_START);
+
+        Some((vaddr as _, num_pages * page_size))
+    }
+}
+
+/// Header of a free block, kept in the free memory itself
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Every block is a multiple of this in size and alignment, so whatever is
+/// left over when splitting a block is big enough to be a block itself
+const UNIT: usize = mem::size_of::<FreeBlock>();
+
+struct HeapState<M: 'static> {
+    morecore: Option<&'static mut M>,
+    /// Free blocks, sorted by address
+    free: *mut FreeBlock,
+}
+
+pub struct Heap<M: MoreCore + 'static = Allocator> {
+    locked: AtomicBool,
+    state: UnsafeCell<HeapState<M>>,
+}
+
+/// Access to the heap state while the lock is held
+struct HeapGuard<'a, M: MoreCore + 'static> {
+    heap: &'a Heap<M>,
+}
+
+unsafe impl<M: MoreCore + Send> Sync for Heap<M> {}
+
+impl<M: MoreCore> Heap<M> {
+    /// An empty heap, it can't grow until init() is called.
+    pub const fn new() -> Heap<M> {
+        Heap {
+            locked: AtomicBool::new(false),
+            state: UnsafeCell::new(HeapState {
+                morecore: None,
+                free: ptr::null_mut(),
+            }),
+        }
+    }
+
+    /// Grow the heap from 'morecore' from now on.
+    ///
+    /// # Safety
+    ///
+    /// 'morecore' must not be used other than through with_allocator() after
+    /// this.
+    pub unsafe fn init(&self, morecore: &'static mut M) {
+        self.lock().morecore = Some(morecore);
+    }
+
+    /// Run 'f' on the allocator the heap grows from, None if init() hasn't
+    /// been called.
+    ///
+    /// 'f' must not allocate from the heap, the heap is locked.
+    pub fn with_allocator<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> Option<R> {
+        let mut state = self.lock();
+
+        state.morecore.as_mut().map(|morecore| f(morecore))
+    }
+
+    fn lock(&self) -> HeapGuard<'_, M> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+
+        HeapGuard { heap: self }
+    }
+}
+
+impl<M: MoreCore> Default for Heap<M> {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+unsafe impl<M: MoreCore + Send> GlobalAlloc for Heap<M> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(&layout);
+
+        self.lock().free_region(ptr as usize, size)
+    }
+}
+
+impl<'a, M: MoreCore> Drop for HeapGuard<'a, M> {
+    fn drop(&mut self) {
+        self.heap.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, M: MoreCore> Deref for HeapGuard<'a, M> {
+    type Target = HeapState<M>;
+
+    fn deref(&self) -> &HeapState<M> {
+        unsafe { &*self.heap.state.get() }
+    }
+}
+
+impl<'a, M: MoreCore> DerefMut for HeapGuard<'a, M> {
+    fn deref_mut(&mut self) -> &mut HeapState<M> {
+        unsafe { &mut *self.heap.state.get() }
+    }
+}
+
+/// Size and alignment of the block used for 'layout'
+fn block_layout(layout: &Layout) -> (usize, usize) {
+    let size = align_up(layout.size(), UNIT);
+    let align = if layout.align() > UNIT {
+        layout.align()
+    } else {
+        UNIT
+    };
+
+    (if size == 0 { UNIT } else { size }, align)
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+impl<M: MoreCore> HeapState<M> {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(&layout);
+
+        if let Some(ptr) = self.alloc_from_free_list(size, align) {
+            return ptr;
+        }
+
+        // Out of memory, get some more and try again
+        let region = match self.morecore {
+            Some(ref mut morecore) => {
+                let wanted = size + align;
+                morecore.more_core(if wanted > MIN_HEAP_GROWTH {
+                    wanted
+                } else {
+                    MIN_HEAP_GROWTH
+                })
+            }
+            None => None,
+        };
+
+        match region {
+            Some((start, region_size)) => {
+                self.free_region(start, region_size);
+                self.alloc_from_free_list(size, align)
+                    .unwrap_or(ptr::null_mut())
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// First fit, splitting off whatever is left in front of and behind the
+    /// allocation as free blocks
+    unsafe fn alloc_from_free_list(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut prev: *mut *mut FreeBlock = &mut self.free;
+
+        while !(*prev).is_null() {
+            let block = *prev;
+            let start = block as usize;
+            let end = start + (*block).size;
+            let alloc_start = align_up(start, align);
+            let alloc_end = alloc_start + size;
+
+            if alloc_end <= end {
+                let mut link: *mut *mut FreeBlock = prev;
+                let next = (*block).next;
+
+                if alloc_start > start {
+                    // Keep the front as a smaller block
+                    (*block).size = alloc_start - start;
+                    link = &mut (*block).next;
+                }
+
+                if alloc_end < end {
+                    let back = alloc_end as *mut FreeBlock;
+                    back.write(FreeBlock {
+                        size: end - alloc_end,
+                        next,
+                    });
+                    *link = back;
+                } else {
+                    *link = next;
+                }
+
+                return Some(alloc_start as *mut u8);
+            }
+
+            prev = &mut (*block).next;
+        }
+
+        None
+    }
+
+    /// Put 'size' bytes at 'start' on the free list, merging it with its
+    /// neighbours
+    unsafe fn free_region(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.free;
+        while !next.is_null() && ((next as usize) < start) {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        if !next.is_null() && ((start + size) == (next as usize)) {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.free = block;
+        } else if ((prev as usize) + (*prev).size) == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 4096;
+
+    /// Hands out pages from a host buffer
+    struct TestCore {
+        buf: Vec<u8>,
+        used: usize,
+        calls: usize,
+    }
+
+    impl MoreCore for TestCore {
+        fn more_core(&mut self, size: usize) -> Option<(usize, usize)> {
+            let size = align_up(size, PAGE_SIZE);
+            let base = align_up(self.buf.as_ptr() as usize, PAGE_SIZE);
+            let start = base + self.used;
+            if (start + size) > (self.buf.as_ptr() as usize + self.buf.len()) {
+                return None;
+            }
+
+            self.used += size;
+            self.calls += 1;
+            Some((start, size))
+        }
+    }
+
+    fn heap(pages: usize) -> &'static Heap<TestCore> {
+        let core = Box::leak(Box::new(TestCore {
+            buf: vec![0; (pages + 1) * PAGE_SIZE],
+            used: 0,
+            calls: 0,
+        }));
+        let heap = Box::leak(Box::new(Heap::new()));
+        unsafe { heap.init(core) };
+        heap
+    }
+
+    fn calls(heap: &Heap<TestCore>) -> usize {
+        heap.with_allocator(|core| core.calls).unwrap()
+    }
+
+    #[test]
+    fn allocations_are_aligned_and_disjoint() {
+        let heap = heap(64);
+        let mut allocs = Vec::new();
+
+        for i in 0..64 {
+            let layout = Layout::from_size_align(1 + i * 7, 1 << (i % 8)).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % layout.align(), 0);
+            unsafe { ptr::write_bytes(ptr, i as u8, layout.size()) };
+            allocs.push((ptr, layout));
+        }
+
+        for (i, &(ptr, layout)) in allocs.iter().enumerate() {
+            let bytes = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+            assert!(bytes.iter().all(|b| *b == i as u8));
+        }
+
+        for (ptr, layout) in allocs {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn heap_grows_on_demand_and_reuses_freed_memory() {
+        let heap = heap(64);
+        assert_eq!(calls(heap), 0);
+
+        let layout = Layout::from_size_align(MIN_HEAP_GROWTH / 2, 8).unwrap();
+        let a = unsafe { heap.alloc(layout) };
+        assert_eq!(calls(heap), 1);
+        let b = unsafe { heap.alloc(layout) };
+        assert_eq!(calls(heap), 1);
+        assert!(!a.is_null() && !b.is_null());
+
+        // Freed blocks coalesce, so the whole growth fits again
+        unsafe {
+            heap.dealloc(a, layout);
+            heap.dealloc(b, layout);
+        }
+        let whole = Layout::from_size_align(MIN_HEAP_GROWTH, 8).unwrap();
+        assert_eq!(unsafe { heap.alloc(whole) }, a);
+        assert_eq!(calls(heap), 1);
+
+        // Bigger than the minimum growth
+        let big = Layout::from_size_align(2 * MIN_HEAP_GROWTH, PAGE_SIZE).unwrap();
+        assert!(!unsafe { heap.alloc(big) }.is_null());
+        assert_eq!(calls(heap), 2);
+    }
+
+    #[test]
+    fn alloc_fails_when_out_of_memory() {
+        let heap = heap(16);
+        let layout = Layout::from_size_align(MIN_HEAP_GROWTH + 1, 8).unwrap();
+        assert!(unsafe { heap.alloc(layout) }.is_null());
+
+        let uninit: Heap<TestCore> = Heap::new();
+        assert!(unsafe { uninit.alloc(Layout::new::<u64>()) }.is_null());
+    }
+
+    #[test]
+    fn allocator_more_core_maps_new_pages() {
+        let mut alloc = ::sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        let (start, size) = alloc.more_core(PAGE_SIZE + 1).unwrap();
+        assert_eq!(size, 2 * PAGE_SIZE);
+        assert!(alloc.kernel.translate(start as _).is_some());
+        assert!(alloc.kernel.translate((start + PAGE_SIZE) as _).is_some());
+    }
+}
@@ -1,21 +1,27 @@
-use super::{Allocator, Error};
+use super::{Allocator, Error, Kernel};
+use arch::DEVICE_VM_ATTRIBUTES;
 use sel4_sys::*;
 
-impl Allocator {
+impl<K: Kernel> Allocator<K> {
+    /// Map the 2^'size_bits' bytes of device memory at 'paddr' uncached,
+    /// returning the vaddr 'paddr' is mapped at.
+    ///
+    /// The window is mapped from the device untypeds, with larger frames
+    /// where it's aligned for them, and doesn't have to be page aligned.
     pub fn io_map(&mut self, paddr: seL4_Word, size_bits: usize) -> Result<seL4_Word, Error> {
-        let vaddr = self.vspace_new_pages_at(
-            Some(paddr),
-            // num_pages
-            //1,
-            (1 << size_bits) / (1 << seL4_PageBits),
-            size_bits,
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+        let offset = paddr & (page_size - 1);
+        let num_pages = (offset + (1 << size_bits) + page_size - 1) / page_size;
+
+        let vaddr = self.vspace_new_large_pages_at(
+            Some(paddr - offset),
+            num_pages as _,
+            seL4_PageBits as _,
             unsafe { seL4_CapRights_new(1, 1, 1) },
-            // no attributes for memory mapped devices
-            0,
+            DEVICE_VM_ATTRIBUTES,
             true,
-            None,
         )?;
 
-        Ok(vaddr)
+        Ok(vaddr + offset)
     }
 }
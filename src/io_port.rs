@@ -0,0 +1,77 @@
+/// x86 IO port capabilities
+///
+/// See https://github.com/seL4/seL4_libs/blob/master/libsel4platsupport/src/arch/x86/io.c
+use super::{Allocator, Error, Kernel, Operation, Vka};
+use sel4_sys::*;
+
+impl<K: Kernel> Allocator<K> {
+    /// Issue a cap to the IO ports 'first_port' to 'last_port' (inclusive)
+    /// from seL4_CapIOPortControl, into a newly allocated cslot.
+    pub fn vka_alloc_io_port(
+        &mut self,
+        first_port: u16,
+        last_port: u16,
+    ) -> Result<seL4_CPtr, Error> {
+        let slot = self.vka_cspace_alloc()?;
+        let path = self.vka_cspace_make_path(slot);
+
+        let err = self.kernel.ioport_control_issue(
+            seL4_CapIOPortControl,
+            first_port as _,
+            last_port as _,
+            path.root,
+            path.cap_ptr,
+            path.cap_depth as _,
+        );
+
+        if let Err(e) = Error::check(Operation::IoPortIssue, err) {
+            self.vka_cspace_free(slot);
+            return Err(e);
+        }
+
+        Ok(slot)
+    }
+
+    /// Delete an IO port cap from vka_alloc_io_port() and release its cslot.
+    pub fn vka_free_io_port(&mut self, cap: seL4_CPtr) -> Result<(), Error> {
+        let path = self.vka_cspace_make_path(cap);
+
+        let err = self
+            .kernel
+            .cnode_delete(path.root, path.cap_ptr, path.cap_depth as _);
+        Error::check(Operation::CNodeDelete, err)?;
+
+        self.vka_cspace_free(cap);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sim;
+    use KernelError;
+
+    #[test]
+    fn io_ports_are_issued_once_until_freed() {
+        // A CNode resolved at less than a word, the paths have to follow
+        let mut alloc = sim::allocator_with_cnode_depth(&[(0x1000_0000, 16, false)], 20);
+        let num_caps = alloc.kernel.num_caps();
+
+        let cap = alloc.vka_alloc_io_port(0x3f8, 0x3ff).unwrap();
+        assert_eq!(
+            alloc.vka_alloc_io_port(0x3fc, 0x400),
+            Err(Error::Kernel(
+                Operation::IoPortIssue,
+                KernelError::RevokeFirst
+            ))
+        );
+        assert_eq!(alloc.num_slots_used, 1);
+
+        alloc.vka_free_io_port(cap).unwrap();
+        assert_eq!(alloc.num_slots_used, 0);
+        assert_eq!(alloc.kernel.num_caps(), num_caps);
+        assert!(alloc.vka_alloc_io_port(0x3fc, 0x400).is_ok());
+    }
+}
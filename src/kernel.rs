@@ -0,0 +1,304 @@
+/// Kernel invocations made by the allocator.
+///
+/// Every seL4 system call the allocator needs goes through this trait so
+/// the allocation logic can be driven by something other than the real
+/// kernel, for example a host-side model when testing off-target.
+use super::{CSpacePath, ObjectType, VmAttributes};
+use sel4_sys::*;
+
+pub trait Kernel {
+    /// seL4_Untyped_Retype, into the slots starting at 'dest'.
+    ///
+    /// Only the root, dest, dest_depth and offset of the path are used, as
+    /// libsel4vka does.
+    fn untyped_retype(
+        &mut self,
+        service: seL4_CPtr,
+        item_type: seL4_Word,
+        size_bits: seL4_Word,
+        dest: &CSpacePath,
+        num_objects: seL4_Word,
+    ) -> seL4_Error;
+
+    /// seL4_ARM_Page_Map
+    fn page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> seL4_Error;
+
+    /// seL4_ARM_Page_Unmap
+    fn page_unmap(&mut self, page: seL4_CPtr) -> seL4_Error;
+
+    /// seL4_MappingFailedLookupLevel, after a map failed with
+    /// seL4_FailedLookup: the bits of virtual address space covered by the
+    /// paging structure that is missing.
+    fn mapping_failed_lookup_level(&self) -> seL4_Word;
+
+    /// Map an intermediate paging structure of type 'obj_type', one of the
+    /// arch::PAGING_LEVELS, e.g. seL4_ARM_PageTable_Map
+    fn paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        attr: VmAttributes,
+    ) -> seL4_Error;
+
+    /// Unmap an intermediate paging structure of type 'obj_type', e.g.
+    /// seL4_ARM_PageTable_Unmap
+    fn paging_structure_unmap(&mut self, obj_type: ObjectType, service: seL4_CPtr) -> seL4_Error;
+
+    /// seL4_CNode_Delete
+    fn cnode_delete(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error;
+
+    /// seL4_SchedControl_Configure
+    #[cfg(feature = "mcs")]
+    fn sched_control_configure(
+        &mut self,
+        service: seL4_CPtr,
+        sched_context: seL4_CPtr,
+        budget: seL4_Time,
+        period: seL4_Time,
+        extra_refills: seL4_Word,
+        badge: seL4_Word,
+    ) -> seL4_Error;
+
+    /// seL4_X86_IOPortControl_Issue
+    #[cfg(feature = "x86_64")]
+    fn ioport_control_issue(
+        &mut self,
+        service: seL4_CPtr,
+        first_port: seL4_Word,
+        last_port: seL4_Word,
+        root: seL4_CPtr,
+        index: seL4_Word,
+        depth: u8,
+    ) -> seL4_Error;
+
+    /// seL4_CNode_Revoke
+    fn cnode_revoke(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error;
+}
+
+/// The real seL4 kernel, invoked through libsel4-sys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sel4Kernel;
+
+impl Kernel for Sel4Kernel {
+    fn untyped_retype(
+        &mut self,
+        service: seL4_CPtr,
+        item_type: seL4_Word,
+        size_bits: seL4_Word,
+        dest: &CSpacePath,
+        num_objects: seL4_Word,
+    ) -> seL4_Error {
+        unsafe {
+            seL4_Untyped_Retype(
+                service,
+                item_type,
+                size_bits,
+                dest.root,
+                dest.dest,
+                dest.dest_depth,
+                dest.offset,
+                num_objects,
+            )
+        }
+    }
+
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        unsafe { seL4_ARM_Page_Map(page, vspace, vaddr, rights, attr) }
+    }
+
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn page_unmap(&mut self, page: seL4_CPtr) -> seL4_Error {
+        unsafe { seL4_ARM_Page_Unmap(page) }
+    }
+
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe {
+                seL4_ARM_PageTable_Map(service, vspace, vaddr, attr)
+            },
+            #[cfg(feature = "aarch64")]
+            ObjectType::PageDirectory => unsafe {
+                seL4_ARM_PageDirectory_Map(service, vspace, vaddr, attr)
+            },
+            #[cfg(feature = "aarch64")]
+            ObjectType::PageUpperDirectory => unsafe {
+                seL4_ARM_PageUpperDirectory_Map(service, vspace, vaddr, attr)
+            },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        unsafe { seL4_X86_Page_Map(page, vspace, vaddr, rights, attr) }
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn page_unmap(&mut self, page: seL4_CPtr) -> seL4_Error {
+        unsafe { seL4_X86_Page_Unmap(page) }
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe {
+                seL4_X86_PageTable_Map(service, vspace, vaddr, attr)
+            },
+            ObjectType::PageDirectory => unsafe {
+                seL4_X86_PageDirectory_Map(service, vspace, vaddr, attr)
+            },
+            ObjectType::Pdpt => unsafe { seL4_X86_PDPT_Map(service, vspace, vaddr, attr) },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        unsafe { seL4_RISCV_Page_Map(page, vspace, vaddr, rights, attr) }
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn page_unmap(&mut self, page: seL4_CPtr) -> seL4_Error {
+        unsafe { seL4_RISCV_Page_Unmap(page) }
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe {
+                seL4_RISCV_PageTable_Map(service, vspace, vaddr, attr)
+            },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn paging_structure_unmap(&mut self, obj_type: ObjectType, service: seL4_CPtr) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe { seL4_ARM_PageTable_Unmap(service) },
+            #[cfg(feature = "aarch64")]
+            ObjectType::PageDirectory => unsafe { seL4_ARM_PageDirectory_Unmap(service) },
+            #[cfg(feature = "aarch64")]
+            ObjectType::PageUpperDirectory => unsafe { seL4_ARM_PageUpperDirectory_Unmap(service) },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn paging_structure_unmap(&mut self, obj_type: ObjectType, service: seL4_CPtr) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe { seL4_X86_PageTable_Unmap(service) },
+            ObjectType::PageDirectory => unsafe { seL4_X86_PageDirectory_Unmap(service) },
+            ObjectType::Pdpt => unsafe { seL4_X86_PDPT_Unmap(service) },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn paging_structure_unmap(&mut self, obj_type: ObjectType, service: seL4_CPtr) -> seL4_Error {
+        match obj_type {
+            ObjectType::PageTable => unsafe { seL4_RISCV_PageTable_Unmap(service) },
+            _ => seL4_Error_seL4_InvalidArgument,
+        }
+    }
+
+    fn mapping_failed_lookup_level(&self) -> seL4_Word {
+        unsafe { seL4_MappingFailedLookupLevel() }
+    }
+
+    fn cnode_delete(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error {
+        unsafe { seL4_CNode_Delete(service, index, depth) }
+    }
+
+    #[cfg(feature = "mcs")]
+    fn sched_control_configure(
+        &mut self,
+        service: seL4_CPtr,
+        sched_context: seL4_CPtr,
+        budget: seL4_Time,
+        period: seL4_Time,
+        extra_refills: seL4_Word,
+        badge: seL4_Word,
+    ) -> seL4_Error {
+        unsafe {
+            seL4_SchedControl_Configure(
+                service,
+                sched_context,
+                budget,
+                period,
+                extra_refills,
+                badge,
+            )
+        }
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn ioport_control_issue(
+        &mut self,
+        service: seL4_CPtr,
+        first_port: seL4_Word,
+        last_port: seL4_Word,
+        root: seL4_CPtr,
+        index: seL4_Word,
+        depth: u8,
+    ) -> seL4_Error {
+        unsafe { seL4_X86_IOPortControl_Issue(service, first_port, last_port, root, index, depth) }
+    }
+
+    fn cnode_revoke(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error {
+        unsafe { seL4_CNode_Revoke(service, index, depth) }
+    }
+}
@@ -10,35 +10,120 @@
 ///
 extern crate sel4_sys;
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+#[cfg(not(any(
+    feature = "aarch32",
+    feature = "aarch64",
+    feature = "x86_64",
+    feature = "riscv64"
+)))]
+compile_error!("one of the aarch32, aarch64, x86_64 or riscv64 features must be enabled");
+
+#[cfg(any(
+    all(feature = "aarch32", feature = "aarch64"),
+    all(feature = "aarch32", feature = "x86_64"),
+    all(feature = "aarch32", feature = "riscv64"),
+    all(feature = "aarch64", feature = "x86_64"),
+    all(feature = "aarch64", feature = "riscv64"),
+    all(feature = "x86_64", feature = "riscv64")
+))]
+compile_error!("only one of the aarch32, aarch64, x86_64 or riscv64 features can be enabled");
+
 use sel4_sys::{seL4_CPtr, seL4_Word};
 
 mod allocator;
+mod arch;
+mod c_vka;
+mod cslot_allocator;
 mod cspacepath;
+mod error;
 mod first_stage_allocator;
+mod heap;
 mod io_map;
+#[cfg(feature = "x86_64")]
+mod io_port;
+mod kernel;
 mod object_allocator;
+mod object_type;
+#[cfg(feature = "mcs")]
+mod sched_control;
+#[cfg(test)]
+mod sim;
+mod slab_allocator;
+mod untyped_allocator;
 mod vka;
 mod vka_object;
 mod vspace;
 
+pub use arch::VmAttributes;
+pub use c_vka::{make_c_vka, sel4twinkle_make_vka, vka_t};
+pub use cspacepath::CSpacePath;
+pub use error::{Error, KernelError, Operation};
+pub use heap::{Heap, MoreCore, MIN_HEAP_GROWTH};
+pub use kernel::{Kernel, Sel4Kernel};
+pub use object_type::ObjectType;
+pub use vka::Vka;
+pub use vka_object::{VkaObject, VkaObjectExt, VkaObjects};
+pub use vspace::{MappedPage, Reservation};
+
 pub const MIN_UNTYPED_SIZE: usize = 4;
 pub const MAX_UNTYPED_SIZE: usize = 32;
 
 // TODO - pull from configs
 pub const MAX_UNTYPED_ITEMS: usize = 256;
 
+// TODO - pull from configs
+/// Upper limit on the number of cslots we can manage
+pub const MAX_CSLOTS: usize = 1 << 14;
+
+// TODO - pull from configs
+/// Initial memory items plus every item split from them
+///
+/// Together with MAX_MAPPINGS this is most of the size of an Allocator,
+/// which is around 75KB on 32-bit and 135KB on 64-bit.
+pub const MAX_UNTYPED_NODES: usize = 1024;
+
+// TODO - pull from configs
+/// Upper limit on the number of object slabs
+pub const MAX_SLABS: usize = 16;
+
+/// Most objects a slab can hold, the kernel's retype fan-out limit
+/// (CONFIG_RETYPE_FAN_OUT_LIMIT)
+pub const MAX_SLAB_OBJECTS: usize = 256;
+
+/// Size (in bits) of the untyped a slab is retyped from, unless changed
+/// with set_slab_size_bits()
+pub const DEFAULT_SLAB_SIZE_BITS: usize = 12;
+
+// TODO - pull from configs
+/// Upper limit on the number of pages mapped by the vspace functions,
+/// unless given a larger table with vspace_set_mappings_table()
+///
+/// Each page or larger frame takes an entry, mapping more than this fails
+/// with Error::MappingsExhausted: 4MB of 4K pages, more with
+/// vspace_new_large_pages_at(). The vspace didn't track its pages before
+/// the shadow page table was added, so this is a new limit for callers
+/// that map a lot of small pages.
+pub const MAX_MAPPINGS: usize = 1024;
+
+/// Upper limit on the number of unmapped ranges of virtual address space
+/// kept for reuse
+pub const MAX_VSPACE_HOLES: usize = 64;
+
+/// Upper limit on the number of paging structures allocated for the vspace
+pub const MAX_PAGING_STRUCTURES: usize = 128;
+
+/// Upper limit on the number of vspace reservations
+pub const MAX_RESERVATIONS: usize = 32;
+
 pub const VKA_NO_PADDR: seL4_Word = 0;
 
 const VSPACE_START: seL4_Word = 0x1000_0000;
 
-// TODO - should be derived from libsel4-sys?
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Error {
-    ResourceExhausted,
-    Other,
-}
-
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct UntypedItem {
     cap: seL4_CPtr,
     size_bits: usize,
@@ -46,24 +131,116 @@ pub struct UntypedItem {
     is_device: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct CapRange {
     first: usize,
     count: usize,
 }
 
-#[derive(Clone, Debug)]
-struct InitUntypedItem {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UntypedState {
+    /// Node isn't tracking an untyped
+    Unused,
+    /// Available for allocation
+    Free,
+    /// Handed out by alloc_untyped()
+    Allocated,
+    /// Retyped into two halves, see UntypedNode::children
+    Split,
+}
+
+/// A node in the buddy tree of untyped memory.
+///
+/// Initial memory items are the roots, splitting a node retypes it into two
+/// untypeds of half the size.
+#[derive(Clone, Copy, Debug)]
+struct UntypedNode {
     item: UntypedItem,
-    is_free: bool,
+    state: UntypedState,
+    /// Node we were split from, None for initial memory items
+    parent: Option<usize>,
+    /// The two halves, only valid when Split
+    children: [usize; 2],
+}
+
+/// Many objects of one type retyped from a single untyped, handed out and
+/// taken back one at a time.
+#[derive(Clone, Copy)]
+struct Slab {
+    /// Type of the objects, None when the slab isn't in use
+    item_type: Option<ObjectType>,
+    /// Untyped the slab was retyped from, freed with the slab
+    ut: seL4_CPtr,
+    /// One object sized untyped per object, retyped from 'ut'
+    uts: CapRange,
+    /// Where the objects are in our CNode, each one retyped from the
+    /// untyped at the same index in 'uts'
+    caps: CapRange,
+    /// Objects handed out and not freed yet
+    num_used: usize,
+    /// One bit per object, set when the object is handed out
+    used_bitmap: [u32; MAX_SLAB_OBJECTS / 32],
+}
+
+/// A paging structure allocated when mapping a page, freed again once
+/// nothing is mapped into it.
+#[derive(Clone, Copy, Debug)]
+struct PagingStructure {
+    /// Index into arch::PAGING_LEVELS
+    level: usize,
+    /// Start of the region it covers
+    vaddr: seL4_Word,
+    obj: VkaObject,
+    /// Pages and paging structures mapped into it
+    num_children: usize,
+}
+
+/// A range of virtual address space
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct VRange {
+    start: seL4_Word,
+    size: seL4_Word,
 }
 
-pub struct Allocator {
-    /// Root page directory for our vspace
-    page_directory: seL4_CPtr,
-    page_table: seL4_CPtr,
+/// Bookkeeping behind a vspace::Reservation
+#[derive(Clone, Copy, Debug, Default)]
+struct ReservationEntry {
+    range: VRange,
+    /// seL4_CapRights bits for pages mapped into the range
+    rights: seL4_Word,
+    cache_attributes: VmAttributes,
+    in_use: bool,
+}
+
+pub struct Allocator<K: Kernel = Sel4Kernel> {
+    /// Kernel invocations are made through this
+    kernel: K,
+
+    /// Root paging structure of our vspace, the page directory on aarch32
+    /// and the page global directory on aarch64
+    vspace_root: seL4_CPtr,
     last_allocated: seL4_Word,
 
+    /// Shadow page table of the pages mapped by the vspace functions,
+    /// sorted by address, the first 'num_mappings' are valid
+    mappings: [MappedPage; MAX_MAPPINGS],
+    /// Used instead of 'mappings' once set by vspace_set_mappings_table()
+    mappings_table: Option<&'static mut [MappedPage]>,
+    num_mappings: usize,
+
+    /// Paging structures we allocated, the first 'num_paging_structures'
+    /// are valid
+    paging_structures: [PagingStructure; MAX_PAGING_STRUCTURES],
+    num_paging_structures: usize,
+
+    /// Unmapped ranges below 'last_allocated', sorted by address, the first
+    /// 'num_vspace_holes' are valid
+    vspace_holes: [VRange; MAX_VSPACE_HOLES],
+    num_vspace_holes: usize,
+
+    /// Reserved ranges, see vspace_reserve_range()
+    reservations: [ReservationEntry; MAX_RESERVATIONS],
+
     /// CNode we allocate from
     root_cnode: seL4_CPtr,
     root_cnode_depth: seL4_CPtr,
@@ -75,10 +252,18 @@ pub struct Allocator {
     /// Number fo slots we've used
     num_slots_used: usize,
 
-    /// Initial memory items
+    /// One bit per slot in 'cslots', set when the slot is in use
+    cslot_bitmap: [u32; MAX_CSLOTS / 32],
+
+    /// Number of initial memory items
     num_init_untyped_items: usize,
-    init_untyped_items: [InitUntypedItem; MAX_UNTYPED_ITEMS],
 
-    /// Untyped memory items we have created
-    untyped_items: [CapRange; (MAX_UNTYPED_SIZE - MIN_UNTYPED_SIZE) + 1],
+    /// Buddy tree of initial memory items and the items split from them
+    untyped_nodes: [UntypedNode; MAX_UNTYPED_NODES],
+
+    /// Object slabs, see slab_alloc()
+    slabs: [Slab; MAX_SLABS],
+
+    /// Size (in bits) of the untyped new slabs are retyped from
+    slab_size_bits: usize,
 }
@@ -1,27 +1,126 @@
-use super::{Allocator, Error};
-use sel4_sys::{seL4_CPtr, seL4_Word};
+use super::{Allocator, CapRange, Error, Kernel, ObjectType};
+use sel4_sys::seL4_CPtr;
 
-impl Allocator {
+impl<K: Kernel> Allocator<K> {
     /// Allocate a single object of the given type.
     pub fn alloc_kobject(
         &mut self,
-        item_type: seL4_Word,
+        item_type: ObjectType,
         item_size: usize,
     ) -> Result<seL4_CPtr, Error> {
-        let size_bits = self.vka_get_object_size(item_type, item_size);
+        let size_bits = self.vka_get_object_size(item_type, item_size)?;
 
         // Allocate an untyped memory item of the right size
         let untyped_mem = self.alloc_untyped(size_bits, None, false)?;
 
         // Allocate an object
-        let cap_range = self.retype_untyped_memory(untyped_mem, item_type, item_size, 1)?;
+        match self.retype_untyped_memory(untyped_mem, item_type, item_size, 1) {
+            Ok(cap_range) => Ok(cap_range.first()),
+            Err(e) => {
+                // The cslot was already released, don't hang on to the memory
+                let _ = self.free_untyped(untyped_mem);
+                Err(e)
+            }
+        }
+    }
+
+    /// Allocate 'num_objects' objects of the given type with a single
+    /// retype.
+    ///
+    /// The objects are carved out of one untyped, big enough for all of them
+    /// once rounded up to a power of two, and placed in a contiguous run of
+    /// cslots. The kernel limits how many objects one retype can create
+    /// (CONFIG_RETYPE_FAN_OUT_LIMIT).
+    ///
+    /// The untyped is returned with the objects' cslots, giving it back with
+    /// free_untyped() destroys the whole batch.
+    pub fn alloc_kobjects(
+        &mut self,
+        item_type: ObjectType,
+        item_size: usize,
+        num_objects: usize,
+    ) -> Result<(seL4_CPtr, CapRange), Error> {
+        let obj_size_bits = self.vka_get_object_size(item_type, item_size)?;
+        let count_bits = num_objects.next_power_of_two().trailing_zeros() as usize;
+
+        // Allocate an untyped memory item big enough for all of them
+        let untyped_mem = self.alloc_untyped(obj_size_bits + count_bits, None, false)?;
+
+        match self.retype_untyped_memory(untyped_mem, item_type, item_size, num_objects) {
+            Ok(cap_range) => Ok((untyped_mem, cap_range)),
+            Err(e) => {
+                // Don't hang on to the memory if we couldn't use it
+                let _ = self.free_untyped(untyped_mem);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sel4_sys::*;
+    use sim;
+    use {KernelError, Operation};
+
+    #[test]
+    fn alloc_kobjects_retypes_a_batch_from_one_untyped() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let num_caps = alloc.kernel.num_caps();
+
+        // 200 endpoints round up to exactly the one 4K untyped, no splits
+        let (ut, eps) = alloc.alloc_kobjects(ObjectType::Endpoint, 0, 200).unwrap();
+        assert_eq!(eps.count(), 200);
+        assert_eq!(alloc.kernel.num_caps(), num_caps + 200);
+
+        assert_eq!(alloc.kernel.cap_parent(eps.first()), Some(ut));
+        for i in 0..eps.count() {
+            let cap = eps.get(i).unwrap();
+            assert_eq!(cap, eps.first() + i as seL4_CPtr);
+            assert_eq!(
+                alloc.kernel.cap_type(cap),
+                Some(api_object_seL4_EndpointObject)
+            );
+            assert_eq!(alloc.kernel.cap_parent(cap), Some(ut));
+        }
+        assert_eq!(eps.get(200), None);
+
+        // Freeing the untyped destroys the batch
+        alloc.free_untyped(ut).unwrap();
+        assert_eq!(alloc.kernel.cap_type(eps.first()), None);
+    }
+
+    #[test]
+    fn alloc_kobject_returns_memory_and_cslot_on_failure() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        alloc
+            .kernel
+            .set_retype_error(api_object_seL4_TCBObject, seL4_Error_seL4_NotEnoughMemory);
+        let num_caps = alloc.kernel.num_caps();
+
+        assert_eq!(
+            alloc.alloc_kobject(ObjectType::Tcb, 0),
+            Err(Error::Kernel(
+                Operation::Retype,
+                KernelError::NotEnoughMemory
+            ))
+        );
+        assert_eq!(alloc.num_slots_used, 0);
+        assert_eq!(alloc.kernel.num_caps(), num_caps);
+        assert!(alloc.alloc_untyped(16, None, false).is_ok());
+    }
 
-        // We should have gotten either zero items (if we ran out of caps), or one
-        // item (if everything went well). If we get more than one, we
-        // miscalculated our sizes
-        assert!((cap_range.count == 0) || (cap_range.count == 1));
+    #[test]
+    fn alloc_kobjects_returns_memory_on_failure() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
 
-        // Return the first item (or zero if none were allocated
-        Ok(cap_range.first as _)
+        // More than the kernel will retype in one go
+        assert_eq!(
+            alloc.alloc_kobjects(ObjectType::Endpoint, 0, 257),
+            Err(Error::Kernel(Operation::Retype, KernelError::RangeError))
+        );
+        assert_eq!(alloc.num_slots_used, 0);
+        assert!(alloc.alloc_untyped(16, None, false).is_ok());
     }
 }
@@ -0,0 +1,612 @@
+/// See https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/kobject_t.h
+/// See https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/object.h
+///
+/// Which object types exist depends on how the kernel was configured,
+/// selected with the cargo features:
+/// - aarch32, aarch64, x86_64, riscv64: the architecture
+/// - mcs: mixed-criticality kernel (scheduling contexts and reply objects)
+/// - hyp: ARM hypervisor support (VCPUs)
+/// - smmu: ARM SMMU support (IO page tables)
+use super::{Error, MAX_UNTYPED_SIZE, MIN_UNTYPED_SIZE};
+use sel4_sys::*;
+
+/// Kernel object types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectType {
+    Untyped,
+    Tcb,
+    Endpoint,
+    Notification,
+    CapTable,
+    #[cfg(feature = "mcs")]
+    SchedContext,
+    #[cfg(feature = "mcs")]
+    Reply,
+    SmallPage,
+    LargePage,
+    #[cfg(feature = "aarch32")]
+    Section,
+    #[cfg(feature = "aarch32")]
+    SuperSection,
+    #[cfg(any(feature = "aarch64", feature = "x86_64", feature = "riscv64"))]
+    HugePage,
+    PageTable,
+    #[cfg(any(feature = "aarch32", feature = "aarch64", feature = "x86_64"))]
+    PageDirectory,
+    #[cfg(feature = "aarch64")]
+    PageUpperDirectory,
+    #[cfg(feature = "aarch64")]
+    PageGlobalDirectory,
+    #[cfg(feature = "x86_64")]
+    Pdpt,
+    #[cfg(feature = "x86_64")]
+    Pml4,
+    #[cfg(all(feature = "hyp", any(feature = "aarch32", feature = "aarch64")))]
+    Vcpu,
+    #[cfg(any(
+        all(feature = "smmu", any(feature = "aarch32", feature = "aarch64")),
+        all(feature = "iommu", feature = "x86_64")
+    ))]
+    IoPageTable,
+}
+
+/// Every object type the kernel was configured with
+const ALL: &[ObjectType] = &[
+    ObjectType::Untyped,
+    ObjectType::Tcb,
+    ObjectType::Endpoint,
+    ObjectType::Notification,
+    ObjectType::CapTable,
+    #[cfg(feature = "mcs")]
+    ObjectType::SchedContext,
+    #[cfg(feature = "mcs")]
+    ObjectType::Reply,
+    ObjectType::SmallPage,
+    ObjectType::LargePage,
+    #[cfg(feature = "aarch32")]
+    ObjectType::Section,
+    #[cfg(feature = "aarch32")]
+    ObjectType::SuperSection,
+    #[cfg(any(feature = "aarch64", feature = "x86_64", feature = "riscv64"))]
+    ObjectType::HugePage,
+    ObjectType::PageTable,
+    #[cfg(any(feature = "aarch32", feature = "aarch64", feature = "x86_64"))]
+    ObjectType::PageDirectory,
+    #[cfg(feature = "aarch64")]
+    ObjectType::PageUpperDirectory,
+    #[cfg(feature = "aarch64")]
+    ObjectType::PageGlobalDirectory,
+    #[cfg(feature = "x86_64")]
+    ObjectType::Pdpt,
+    #[cfg(feature = "x86_64")]
+    ObjectType::Pml4,
+    #[cfg(all(feature = "hyp", any(feature = "aarch32", feature = "aarch64")))]
+    ObjectType::Vcpu,
+    #[cfg(any(
+        all(feature = "smmu", any(feature = "aarch32", feature = "aarch64")),
+        all(feature = "iommu", feature = "x86_64")
+    ))]
+    ObjectType::IoPageTable,
+];
+
+impl ObjectType {
+    /// The object type for one of the kernel's constants, None if the
+    /// kernel doesn't have such an object.
+    pub fn from_sel4(obj_type: seL4_Word) -> Option<ObjectType> {
+        ALL.iter().cloned().find(|t| t.to_sel4() == obj_type)
+    }
+
+    /// The kernel's constant for this object type, as given to
+    /// seL4_Untyped_Retype.
+    pub fn to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::Untyped => api_object_seL4_UntypedObject,
+            ObjectType::Tcb => api_object_seL4_TCBObject,
+            ObjectType::Endpoint => api_object_seL4_EndpointObject,
+            ObjectType::Notification => api_object_seL4_NotificationObject,
+            ObjectType::CapTable => api_object_seL4_CapTableObject,
+            #[cfg(feature = "mcs")]
+            ObjectType::SchedContext => api_object_seL4_SchedContextObject,
+            #[cfg(feature = "mcs")]
+            ObjectType::Reply => api_object_seL4_ReplyObject,
+            _ => return self.arch_to_sel4(),
+        };
+
+        obj_type as _
+    }
+
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn arch_to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::SmallPage => _object_seL4_ARM_SmallPageObject,
+            ObjectType::LargePage => _object_seL4_ARM_LargePageObject,
+            ObjectType::PageTable => _object_seL4_ARM_PageTableObject,
+            ObjectType::PageDirectory => _object_seL4_ARM_PageDirectoryObject,
+            #[cfg(feature = "hyp")]
+            ObjectType::Vcpu => _object_seL4_ARM_VCPUObject,
+            #[cfg(feature = "smmu")]
+            ObjectType::IoPageTable => _object_seL4_ARM_IOPageTableObject,
+            _ => return self.mode_to_sel4(),
+        };
+
+        obj_type as _
+    }
+
+    #[cfg(feature = "aarch32")]
+    fn mode_to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::Section => _object_seL4_ARM_SectionObject,
+            ObjectType::SuperSection => _object_seL4_ARM_SuperSectionObject,
+            _ => unreachable!(),
+        };
+
+        obj_type as _
+    }
+
+    #[cfg(feature = "aarch64")]
+    fn mode_to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::HugePage => _mode_object_seL4_ARM_HugePageObject,
+            ObjectType::PageUpperDirectory => _mode_object_seL4_ARM_PageUpperDirectoryObject,
+            ObjectType::PageGlobalDirectory => _mode_object_seL4_ARM_PageGlobalDirectoryObject,
+            _ => unreachable!(),
+        };
+
+        obj_type as _
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn arch_to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::SmallPage => _object_seL4_X86_4K,
+            ObjectType::LargePage => _object_seL4_X86_LargePageObject,
+            ObjectType::PageTable => _object_seL4_X86_PageTableObject,
+            ObjectType::PageDirectory => _object_seL4_X86_PageDirectoryObject,
+            ObjectType::HugePage => _mode_object_seL4_X64_HugePageObject,
+            ObjectType::Pdpt => _mode_object_seL4_X86_PDPTObject,
+            ObjectType::Pml4 => _mode_object_seL4_X64_PML4Object,
+            #[cfg(feature = "iommu")]
+            ObjectType::IoPageTable => _object_seL4_X86_IOPageTableObject,
+            _ => unreachable!(),
+        };
+
+        obj_type as _
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn arch_to_sel4(self) -> seL4_Word {
+        let obj_type = match self {
+            ObjectType::SmallPage => _object_seL4_RISCV_4K_Page,
+            ObjectType::LargePage => _object_seL4_RISCV_Mega_Page,
+            ObjectType::HugePage => _mode_object_seL4_RISCV_Giga_Page,
+            ObjectType::PageTable => _object_seL4_RISCV_PageTableObject,
+            _ => unreachable!(),
+        };
+
+        obj_type as _
+    }
+
+    /// The frame object type for a frame of 'size_bits' bits.
+    ///
+    /// See kobject_get_type() in libsel4vka.
+    pub fn frame(size_bits: usize) -> Result<ObjectType, Error> {
+        #[allow(non_upper_case_globals)]
+        match size_bits as u32 {
+            seL4_PageBits => Ok(ObjectType::SmallPage),
+            seL4_LargePageBits => Ok(ObjectType::LargePage),
+            #[cfg(feature = "aarch32")]
+            seL4_SectionBits => Ok(ObjectType::Section),
+            #[cfg(feature = "aarch32")]
+            seL4_SuperSectionBits => Ok(ObjectType::SuperSection),
+            #[cfg(any(feature = "aarch64", feature = "x86_64", feature = "riscv64"))]
+            seL4_HugePageBits => Ok(ObjectType::HugePage),
+            _ => Err(Error::SizeOutOfRange(size_bits)),
+        }
+    }
+
+    /// Get the size (in bits) of the untyped memory required to create an
+    /// object of this type.
+    ///
+    /// 'size_bits' is only used by objects that don't have a fixed size,
+    /// the size of an Untyped or SchedContext, or the number of slots (in
+    /// bits) of a CapTable.
+    pub fn size_bits(self, size_bits: usize) -> Result<usize, Error> {
+        let bits = match self {
+            ObjectType::Untyped => {
+                if !(MIN_UNTYPED_SIZE..=MAX_UNTYPED_SIZE).contains(&size_bits) {
+                    return Err(Error::SizeOutOfRange(size_bits));
+                }
+                size_bits
+            }
+            ObjectType::Tcb => seL4_TCBBits as _,
+            ObjectType::Endpoint => seL4_EndpointBits as _,
+            ObjectType::Notification => seL4_NotificationBits as _,
+            ObjectType::CapTable => {
+                let bits = seL4_SlotBits as usize + size_bits;
+                if (size_bits == 0) || (bits > MAX_UNTYPED_SIZE) {
+                    return Err(Error::SizeOutOfRange(size_bits));
+                }
+                bits
+            }
+            #[cfg(feature = "mcs")]
+            ObjectType::SchedContext => {
+                if (size_bits < seL4_MinSchedContextBits as usize) || (size_bits > MAX_UNTYPED_SIZE)
+                {
+                    return Err(Error::SizeOutOfRange(size_bits));
+                }
+                size_bits
+            }
+            #[cfg(feature = "mcs")]
+            ObjectType::Reply => seL4_ReplyBits as _,
+            _ => self.arch_size_bits(),
+        };
+
+        Ok(bits)
+    }
+
+    /// The inverse of size_bits(), the 'size_bits' to create an object of
+    /// this type that takes 2^'memory_bits' bytes of untyped memory.
+    ///
+    /// libsel4vka hands its utspace functions the memory size.
+    pub fn size_bits_for_memory(self, memory_bits: usize) -> Result<usize, Error> {
+        let size_bits = match self {
+            ObjectType::Untyped => memory_bits,
+            ObjectType::CapTable => memory_bits.saturating_sub(seL4_SlotBits as _),
+            #[cfg(feature = "mcs")]
+            ObjectType::SchedContext => memory_bits,
+            _ => 0,
+        };
+
+        if self.size_bits(size_bits) != Ok(memory_bits) {
+            return Err(Error::SizeOutOfRange(memory_bits));
+        }
+
+        Ok(size_bits)
+    }
+
+    /// Size (in bits) of the architecture specific objects, these all have a
+    /// fixed size.
+    #[cfg(any(feature = "aarch32", feature = "aarch64"))]
+    fn arch_size_bits(self) -> usize {
+        let bits = match self {
+            ObjectType::SmallPage => seL4_PageBits,
+            ObjectType::LargePage => seL4_LargePageBits,
+            ObjectType::PageTable => seL4_PageTableBits,
+            ObjectType::PageDirectory => seL4_PageDirBits,
+            #[cfg(feature = "hyp")]
+            ObjectType::Vcpu => seL4_ARM_VCPUBits,
+            #[cfg(feature = "smmu")]
+            ObjectType::IoPageTable => seL4_IOPageTableBits,
+            _ => return self.mode_size_bits(),
+        };
+
+        bits as _
+    }
+
+    #[cfg(feature = "aarch32")]
+    fn mode_size_bits(self) -> usize {
+        let bits = match self {
+            ObjectType::Section => seL4_SectionBits,
+            ObjectType::SuperSection => seL4_SuperSectionBits,
+            _ => unreachable!(),
+        };
+
+        bits as _
+    }
+
+    #[cfg(feature = "aarch64")]
+    fn mode_size_bits(self) -> usize {
+        let bits = match self {
+            ObjectType::HugePage => seL4_HugePageBits,
+            ObjectType::PageUpperDirectory => seL4_PUDBits,
+            ObjectType::PageGlobalDirectory => seL4_PGDBits,
+            _ => unreachable!(),
+        };
+
+        bits as _
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn arch_size_bits(self) -> usize {
+        let bits = match self {
+            ObjectType::SmallPage => seL4_PageBits,
+            ObjectType::LargePage => seL4_LargePageBits,
+            ObjectType::HugePage => seL4_HugePageBits,
+            ObjectType::PageTable => seL4_PageTableBits,
+            ObjectType::PageDirectory => seL4_PageDirBits,
+            ObjectType::Pdpt => seL4_PDPTBits,
+            ObjectType::Pml4 => seL4_PML4Bits,
+            #[cfg(feature = "iommu")]
+            ObjectType::IoPageTable => seL4_IOPageTableBits,
+            _ => unreachable!(),
+        };
+
+        bits as _
+    }
+
+    #[cfg(feature = "riscv64")]
+    fn arch_size_bits(self) -> usize {
+        let bits = match self {
+            ObjectType::SmallPage => seL4_PageBits,
+            ObjectType::LargePage => seL4_LargePageBits,
+            ObjectType::HugePage => seL4_HugePageBits,
+            ObjectType::PageTable => seL4_PageTableBits,
+            _ => unreachable!(),
+        };
+
+        bits as _
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(obj_type: ObjectType, sel4_type: seL4_Word, bits: u32) {
+        assert_eq!(obj_type.to_sel4(), sel4_type as seL4_Word);
+        assert_eq!(obj_type.size_bits(0), Ok(bits as usize));
+    }
+
+    #[test]
+    fn kernel_objects_match_libsel4() {
+        check(ObjectType::Tcb, api_object_seL4_TCBObject, seL4_TCBBits);
+        check(
+            ObjectType::Endpoint,
+            api_object_seL4_EndpointObject,
+            seL4_EndpointBits,
+        );
+        check(
+            ObjectType::Notification,
+            api_object_seL4_NotificationObject,
+            seL4_NotificationBits,
+        );
+        #[cfg(feature = "mcs")]
+        check(
+            ObjectType::Reply,
+            api_object_seL4_ReplyObject,
+            seL4_ReplyBits,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "aarch32")]
+    fn aarch32_objects_match_libsel4() {
+        check(
+            ObjectType::SmallPage,
+            _object_seL4_ARM_SmallPageObject,
+            seL4_PageBits,
+        );
+        check(
+            ObjectType::LargePage,
+            _object_seL4_ARM_LargePageObject,
+            seL4_LargePageBits,
+        );
+        check(
+            ObjectType::Section,
+            _object_seL4_ARM_SectionObject,
+            seL4_SectionBits,
+        );
+        check(
+            ObjectType::SuperSection,
+            _object_seL4_ARM_SuperSectionObject,
+            seL4_SuperSectionBits,
+        );
+        check(
+            ObjectType::PageTable,
+            _object_seL4_ARM_PageTableObject,
+            seL4_PageTableBits,
+        );
+        check(
+            ObjectType::PageDirectory,
+            _object_seL4_ARM_PageDirectoryObject,
+            seL4_PageDirBits,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "aarch64")]
+    fn aarch64_objects_match_libsel4() {
+        check(
+            ObjectType::SmallPage,
+            _object_seL4_ARM_SmallPageObject,
+            seL4_PageBits,
+        );
+        check(
+            ObjectType::LargePage,
+            _object_seL4_ARM_LargePageObject,
+            seL4_LargePageBits,
+        );
+        check(
+            ObjectType::HugePage,
+            _mode_object_seL4_ARM_HugePageObject,
+            seL4_HugePageBits,
+        );
+        check(
+            ObjectType::PageTable,
+            _object_seL4_ARM_PageTableObject,
+            seL4_PageTableBits,
+        );
+        check(
+            ObjectType::PageDirectory,
+            _object_seL4_ARM_PageDirectoryObject,
+            seL4_PageDirBits,
+        );
+        check(
+            ObjectType::PageUpperDirectory,
+            _mode_object_seL4_ARM_PageUpperDirectoryObject,
+            seL4_PUDBits,
+        );
+        check(
+            ObjectType::PageGlobalDirectory,
+            _mode_object_seL4_ARM_PageGlobalDirectoryObject,
+            seL4_PGDBits,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "x86_64")]
+    fn x86_64_objects_match_libsel4() {
+        check(ObjectType::SmallPage, _object_seL4_X86_4K, seL4_PageBits);
+        check(
+            ObjectType::LargePage,
+            _object_seL4_X86_LargePageObject,
+            seL4_LargePageBits,
+        );
+        check(
+            ObjectType::HugePage,
+            _mode_object_seL4_X64_HugePageObject,
+            seL4_HugePageBits,
+        );
+        check(
+            ObjectType::PageTable,
+            _object_seL4_X86_PageTableObject,
+            seL4_PageTableBits,
+        );
+        check(
+            ObjectType::PageDirectory,
+            _object_seL4_X86_PageDirectoryObject,
+            seL4_PageDirBits,
+        );
+        check(
+            ObjectType::Pdpt,
+            _mode_object_seL4_X86_PDPTObject,
+            seL4_PDPTBits,
+        );
+        check(
+            ObjectType::Pml4,
+            _mode_object_seL4_X64_PML4Object,
+            seL4_PML4Bits,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn riscv64_objects_match_libsel4() {
+        check(
+            ObjectType::SmallPage,
+            _object_seL4_RISCV_4K_Page,
+            seL4_PageBits,
+        );
+        check(
+            ObjectType::LargePage,
+            _object_seL4_RISCV_Mega_Page,
+            seL4_LargePageBits,
+        );
+        check(
+            ObjectType::HugePage,
+            _mode_object_seL4_RISCV_Giga_Page,
+            seL4_HugePageBits,
+        );
+        check(
+            ObjectType::PageTable,
+            _object_seL4_RISCV_PageTableObject,
+            seL4_PageTableBits,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mcs")]
+    fn sched_contexts_match_libsel4() {
+        let min_bits = seL4_MinSchedContextBits as usize;
+        assert_eq!(
+            ObjectType::SchedContext.to_sel4(),
+            api_object_seL4_SchedContextObject
+        );
+        assert_eq!(ObjectType::SchedContext.size_bits(min_bits), Ok(min_bits));
+        assert_eq!(
+            ObjectType::SchedContext.size_bits(min_bits + 2),
+            Ok(min_bits + 2)
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "hyp", any(feature = "aarch32", feature = "aarch64")))]
+    fn vcpus_match_libsel4() {
+        check(
+            ObjectType::Vcpu,
+            _object_seL4_ARM_VCPUObject,
+            seL4_ARM_VCPUBits,
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "smmu", any(feature = "aarch32", feature = "aarch64")))]
+    fn arm_io_page_tables_match_libsel4() {
+        check(
+            ObjectType::IoPageTable,
+            _object_seL4_ARM_IOPageTableObject,
+            seL4_IOPageTableBits,
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "iommu", feature = "x86_64"))]
+    fn x86_io_page_tables_match_libsel4() {
+        check(
+            ObjectType::IoPageTable,
+            _object_seL4_X86_IOPageTableObject,
+            seL4_IOPageTableBits,
+        );
+    }
+
+    #[test]
+    fn variable_sized_objects_are_range_checked() {
+        assert_eq!(ObjectType::Untyped.size_bits(12), Ok(12));
+        assert!(ObjectType::Untyped.size_bits(MIN_UNTYPED_SIZE - 1).is_err());
+        assert!(ObjectType::Untyped.size_bits(MAX_UNTYPED_SIZE + 1).is_err());
+
+        assert_eq!(
+            ObjectType::CapTable.size_bits(8),
+            Ok(seL4_SlotBits as usize + 8)
+        );
+        assert!(ObjectType::CapTable.size_bits(0).is_err());
+
+        #[cfg(feature = "mcs")]
+        {
+            let min_bits = seL4_MinSchedContextBits as usize;
+            assert_eq!(ObjectType::SchedContext.size_bits(min_bits), Ok(min_bits));
+            assert!(ObjectType::SchedContext.size_bits(min_bits - 1).is_err());
+        }
+    }
+
+    #[test]
+    fn from_sel4_is_the_inverse_of_to_sel4() {
+        for obj_type in ALL.iter() {
+            assert_eq!(ObjectType::from_sel4(obj_type.to_sel4()), Some(*obj_type));
+        }
+        assert_eq!(ObjectType::from_sel4(!0), None);
+    }
+
+    #[test]
+    fn size_bits_for_memory_is_the_inverse_of_size_bits() {
+        for obj_type in ALL.iter() {
+            let size_bits = match *obj_type {
+                ObjectType::Untyped => 12,
+                ObjectType::CapTable => 4,
+                #[cfg(feature = "mcs")]
+                ObjectType::SchedContext => seL4_MinSchedContextBits as _,
+                _ => 0,
+            };
+            let memory_bits = obj_type.size_bits(size_bits).unwrap();
+            assert_eq!(obj_type.size_bits_for_memory(memory_bits), Ok(size_bits));
+        }
+
+        assert_eq!(
+            ObjectType::Tcb.size_bits_for_memory(4),
+            Err(Error::SizeOutOfRange(4))
+        );
+        assert_eq!(
+            ObjectType::CapTable.size_bits_for_memory(seL4_SlotBits as _),
+            Err(Error::SizeOutOfRange(seL4_SlotBits as _))
+        );
+    }
+
+    #[test]
+    fn frame_types_match_their_size() {
+        for obj_type in [ObjectType::SmallPage, ObjectType::LargePage].iter() {
+            let bits = obj_type.size_bits(0).unwrap();
+            assert_eq!(ObjectType::frame(bits), Ok(*obj_type));
+        }
+        assert!(ObjectType::frame(seL4_PageBits as usize + 1).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+/// Scheduling context configuration on an MCS kernel
+///
+/// See https://github.com/seL4/seL4_libs/blob/master/libsel4utils/src/thread.c
+use super::{Allocator, Error, Kernel, Operation};
+use sel4_sys::*;
+
+impl<K: Kernel> Allocator<K> {
+    /// Configure 'sched_context' through 'sched_control' so it gets 'budget'
+    /// microseconds of CPU time every 'period' microseconds.
+    ///
+    /// There's a sched control cap per core, bootinfo->schedcontrol, the
+    /// one used picks the core the sched context runs threads on.
+    pub fn sched_context_configure(
+        &mut self,
+        sched_control: seL4_CPtr,
+        sched_context: seL4_CPtr,
+        budget: seL4_Time,
+        period: seL4_Time,
+    ) -> Result<(), Error> {
+        let err =
+            self.kernel
+                .sched_control_configure(sched_control, sched_context, budget, period, 0, 0);
+        Error::check(Operation::SchedControlConfigure, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sim::{self, SIM_NUM_NODES, SIM_SCHED_CONTROL};
+    use {KernelError, VkaObjectExt};
+
+    #[test]
+    fn configure_sets_budget_and_period() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let sc = alloc
+            .vka_alloc_sched_context(seL4_MinSchedContextBits as _)
+            .unwrap();
+
+        alloc
+            .sched_context_configure(SIM_SCHED_CONTROL, sc.cptr, 1000, 5000)
+            .unwrap();
+        assert_eq!(alloc.kernel.sched_params(sc.cptr), Some((1000, 5000)));
+
+        // The budget can't be more than the period
+        assert!(alloc
+            .sched_context_configure(SIM_SCHED_CONTROL, sc.cptr, 5001, 5000)
+            .is_err());
+        assert_eq!(alloc.kernel.sched_params(sc.cptr), Some((1000, 5000)));
+    }
+
+    #[test]
+    fn configure_goes_through_the_given_cores_sched_control() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let sc = alloc
+            .vka_alloc_sched_context(seL4_MinSchedContextBits as _)
+            .unwrap();
+
+        let last_core = SIM_SCHED_CONTROL + SIM_NUM_NODES - 1;
+        alloc
+            .sched_context_configure(last_core, sc.cptr, 1000, 5000)
+            .unwrap();
+        assert_eq!(alloc.kernel.sched_params(sc.cptr), Some((1000, 5000)));
+
+        assert_eq!(
+            alloc.sched_context_configure(last_core + 1, sc.cptr, 1000, 5000),
+            Err(Error::Kernel(
+                Operation::SchedControlConfigure,
+                KernelError::InvalidCapability
+            ))
+        );
+    }
+}
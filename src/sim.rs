@@ -0,0 +1,1222 @@
+/// A host-side model of the parts of the seL4 kernel the allocator uses.
+///
+/// Models untyped objects with watermarks, a single CNode of slots,
+/// capability derivation and the paging structures of arch::PAGING_LEVELS
+/// below the vspace root. Invocations are checked the way the kernel checks
+/// them, so invalid operations fail with the same seL4_Error the kernel
+/// would give.
+///
+/// See https://github.com/seL4/seL4/blob/master/src/object/untyped.c
+/// See https://github.com/seL4/seL4/blob/master/src/arch/arm/32/kernel/vspace.c
+/// See https://github.com/seL4/seL4/blob/master/src/arch/arm/64/kernel/vspace.c
+/// See https://github.com/seL4/seL4/blob/master/src/arch/x86/64/kernel/vspace.c
+/// See https://github.com/seL4/seL4/blob/master/src/arch/riscv/kernel/vspace.c
+use super::{Allocator, CSpacePath, Kernel, ObjectType, VmAttributes};
+use arch::{FRAME_SIZES, PAGING_LEVELS};
+use sel4_sys::*;
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+/// Slot holding the cap to the (only) CNode
+pub const SIM_CNODE: seL4_CPtr = seL4_CapInitThreadCNode;
+
+/// Slot holding the cap to the vspace root
+pub const SIM_VSPACE: seL4_CPtr = 3;
+
+/// Slots holding the sched control caps, one per core, as in
+/// bootinfo->schedcontrol
+#[cfg(feature = "mcs")]
+pub const SIM_SCHED_CONTROL: seL4_CPtr = 16;
+#[cfg(feature = "mcs")]
+pub const SIM_NUM_NODES: seL4_CPtr = 2;
+
+/// First slot handed to the allocator, everything below is reserved for
+/// the initial caps and the untypeds given to the simulation
+pub const SIM_FIRST_FREE_SLOT: seL4_CPtr = 64;
+
+/// Number of slots in the simulated CNode
+pub const SIM_CNODE_SLOTS: usize = 4096;
+
+/// Upper limit on objects per retype (CONFIG_RETYPE_FAN_OUT_LIMIT)
+const RETYPE_FAN_OUT_LIMIT: seL4_Word = 256;
+
+/// Type of the vspace root, above the arch::PAGING_LEVELS
+#[cfg(feature = "aarch32")]
+const VSPACE_ROOT: ObjectType = ObjectType::PageDirectory;
+#[cfg(all(feature = "aarch64", not(feature = "hyp")))]
+const VSPACE_ROOT: ObjectType = ObjectType::PageGlobalDirectory;
+#[cfg(all(feature = "aarch64", feature = "hyp"))]
+const VSPACE_ROOT: ObjectType = ObjectType::PageUpperDirectory;
+#[cfg(feature = "x86_64")]
+const VSPACE_ROOT: ObjectType = ObjectType::Pml4;
+#[cfg(feature = "riscv64")]
+const VSPACE_ROOT: ObjectType = ObjectType::PageTable;
+
+/// Start of the kernel window, user mappings must be below it
+#[cfg(feature = "aarch32")]
+const KERNEL_BASE: seL4_Word = 0xe000_0000;
+#[cfg(all(feature = "aarch64", not(feature = "hyp")))]
+const KERNEL_BASE: seL4_Word = 1 << 48;
+#[cfg(all(feature = "aarch64", feature = "hyp"))]
+const KERNEL_BASE: seL4_Word = 1 << 40;
+#[cfg(any(feature = "x86_64", all(feature = "riscv64", feature = "sv48")))]
+const KERNEL_BASE: seL4_Word = 1 << 47;
+#[cfg(all(feature = "riscv64", not(feature = "sv48")))]
+const KERNEL_BASE: seL4_Word = 1 << 38;
+
+#[derive(Clone, Debug)]
+enum ObjectKind {
+    Untyped {
+        is_device: bool,
+        watermark: seL4_Word,
+    },
+    Frame {
+        mapped: Option<seL4_Word>,
+    },
+    /// The vspace root or one of the arch::PAGING_LEVELS
+    PagingStructure {
+        /// Levels below the vspace root once mapped, the root is at 0
+        depth: Option<usize>,
+        /// By vaddr >> entry_bits(depth)
+        entries: BTreeMap<seL4_Word, Entry>,
+    },
+    CNode,
+    #[cfg(feature = "mcs")]
+    SchedContext {
+        /// (budget, period) once configured
+        params: Option<(seL4_Time, seL4_Time)>,
+    },
+    #[cfg(feature = "x86_64")]
+    IoPort {
+        first: seL4_Word,
+        last: seL4_Word,
+    },
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Entry {
+    PagingStructure(usize),
+    Frame(usize),
+}
+
+/// Why a walk down the paging structures stopped short
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WalkError {
+    /// No paging structure at this depth
+    Missing(usize),
+    /// A frame is mapped where a paging structure would be
+    Frame,
+}
+
+#[derive(Clone, Debug)]
+struct Object {
+    item_type: seL4_Word,
+    paddr: seL4_Word,
+    size_bits: usize,
+    kind: ObjectKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Cap {
+    object: usize,
+    parent: Option<seL4_CPtr>,
+}
+
+pub struct SimKernel {
+    slots: BTreeMap<seL4_CPtr, Cap>,
+    objects: Vec<Object>,
+    /// Rights bits and attributes each mapped frame was mapped with
+    frame_attributes: BTreeMap<usize, (seL4_Word, VmAttributes)>,
+    /// Depth the CNode's slots are resolved at
+    cnode_depth: seL4_Word,
+    /// seL4_MappingFailedLookupLevel() after the last failed map
+    failed_lookup_level: seL4_Word,
+    /// Error for page maps that find their paging structure, see
+    /// set_page_map_error()
+    page_map_error: seL4_Error,
+    /// Object type whose retypes fail and how, see set_retype_error()
+    retype_error: Option<(seL4_Word, seL4_Error)>,
+}
+
+impl SimKernel {
+    /// A kernel with only the CNode and vspace root caps.
+    pub fn new() -> SimKernel {
+        let mut kernel = SimKernel {
+            slots: BTreeMap::new(),
+            objects: Vec::new(),
+            frame_attributes: BTreeMap::new(),
+            cnode_depth: seL4_WordBits as _,
+            failed_lookup_level: 0,
+            page_map_error: seL4_Error_seL4_NoError,
+            retype_error: None,
+        };
+
+        kernel.insert_object(
+            SIM_CNODE,
+            None,
+            Object {
+                item_type: api_object_seL4_CapTableObject,
+                paddr: 0,
+                size_bits: 0,
+                kind: ObjectKind::CNode,
+            },
+        );
+        kernel.insert_object(
+            SIM_VSPACE,
+            None,
+            Object {
+                item_type: VSPACE_ROOT.to_sel4(),
+                paddr: 0,
+                size_bits: VSPACE_ROOT.size_bits(0).unwrap(),
+                kind: ObjectKind::PagingStructure {
+                    depth: Some(0),
+                    entries: BTreeMap::new(),
+                },
+            },
+        );
+
+        kernel
+    }
+
+    /// Resolve the CNode's slots at 'depth' bits rather than a whole word,
+    /// as with a root CNode that has a guard.
+    pub fn set_cnode_depth(&mut self, depth: seL4_Word) {
+        self.cnode_depth = depth;
+    }
+
+    /// Fail every page map that gets as far as finding its paging structure
+    /// with 'err', a mapping the kernel refuses late.
+    pub fn set_page_map_error(&mut self, err: seL4_Error) {
+        self.page_map_error = err;
+    }
+
+    /// Fail every retype into objects of 'item_type' with 'err', retypes
+    /// into other types still work.
+    pub fn set_retype_error(&mut self, item_type: seL4_Word, err: seL4_Error) {
+        self.retype_error = Some((item_type, err));
+    }
+
+    /// Place an original untyped cap at 'slot', as the kernel does for the
+    /// bootinfo untyped list.
+    pub fn add_untyped(
+        &mut self,
+        slot: seL4_CPtr,
+        paddr: seL4_Word,
+        size_bits: usize,
+        is_device: bool,
+    ) {
+        assert!(!self.slots.contains_key(&slot));
+        self.insert_object(
+            slot,
+            None,
+            Object {
+                item_type: api_object_seL4_UntypedObject,
+                paddr,
+                size_bits,
+                kind: ObjectKind::Untyped {
+                    is_device,
+                    watermark: 0,
+                },
+            },
+        );
+    }
+
+    /// Map a paging structure at 'level' of arch::PAGING_LEVELS covering
+    /// 'vaddr' that no cap we hold refers to, like those the kernel made
+    /// for the root task's image.
+    pub fn add_paging_structure(&mut self, level: usize, vaddr: seL4_Word) {
+        let obj_type = PAGING_LEVELS[level].obj_type;
+        let parent = self
+            .walk(self.vspace_root(), vaddr, level)
+            .expect("no paging structure above");
+
+        self.objects.push(Object {
+            item_type: obj_type.to_sel4(),
+            paddr: 0,
+            size_bits: obj_type.size_bits(0).unwrap(),
+            kind: ObjectKind::PagingStructure {
+                depth: Some(level + 1),
+                entries: BTreeMap::new(),
+            },
+        });
+        let object = self.objects.len() - 1;
+        self.entries_mut(parent)
+            .insert(vaddr >> entry_bits(level), Entry::PagingStructure(object));
+    }
+
+    /// Is there a paging structure at 'level' of arch::PAGING_LEVELS
+    /// covering 'vaddr'.
+    pub fn has_paging_structure(&self, level: usize, vaddr: seL4_Word) -> bool {
+        self.walk(self.vspace_root(), vaddr, level + 1).is_ok()
+    }
+
+    /// Type of the object the cap in 'slot' refers to.
+    pub fn cap_type(&self, slot: seL4_CPtr) -> Option<seL4_Word> {
+        self.slots
+            .get(&slot)
+            .map(|cap| self.objects[cap.object].item_type)
+    }
+
+    /// Size of the object the cap in 'slot' refers to.
+    pub fn cap_size_bits(&self, slot: seL4_CPtr) -> Option<usize> {
+        self.slots
+            .get(&slot)
+            .map(|cap| self.objects[cap.object].size_bits)
+    }
+
+    /// Physical address of the object the cap in 'slot' refers to.
+    pub fn cap_paddr(&self, slot: seL4_CPtr) -> Option<seL4_Word> {
+        self.slots
+            .get(&slot)
+            .map(|cap| self.objects[cap.object].paddr)
+    }
+
+    /// Slot of the cap the cap in 'slot' was derived from.
+    pub fn cap_parent(&self, slot: seL4_CPtr) -> Option<seL4_CPtr> {
+        self.slots.get(&slot).and_then(|cap| cap.parent)
+    }
+
+    /// Number of caps in the CNode.
+    pub fn num_caps(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Walk the paging structures from the vspace root and translate
+    /// 'vaddr'.
+    pub fn translate(&self, vaddr: seL4_Word) -> Option<seL4_Word> {
+        let frame = self.frame_at(vaddr)?;
+
+        let offset = vaddr & ((1 << self.objects[frame].size_bits) - 1);
+        Some(self.objects[frame].paddr + offset)
+    }
+
+    /// Rights bits and attributes of the mapping at 'vaddr'.
+    pub fn mapping_attributes(&self, vaddr: seL4_Word) -> Option<(seL4_Word, VmAttributes)> {
+        self.frame_attributes.get(&self.frame_at(vaddr)?).cloned()
+    }
+
+    /// Frame mapped at 'vaddr' in the vspace
+    fn frame_at(&self, vaddr: seL4_Word) -> Option<usize> {
+        let mut table = self.vspace_root();
+        for depth in 0..=PAGING_LEVELS.len() {
+            match self.entries(table).get(&(vaddr >> entry_bits(depth)))? {
+                Entry::PagingStructure(next) => table = *next,
+                Entry::Frame(frame) => return Some(*frame),
+            }
+        }
+        None
+    }
+
+    /// Budget and period of the scheduling context in 'slot', if configured.
+    #[cfg(feature = "mcs")]
+    pub fn sched_params(&self, slot: seL4_CPtr) -> Option<(seL4_Time, seL4_Time)> {
+        match self.objects[self.slots.get(&slot)?.object].kind {
+            ObjectKind::SchedContext { params } => params,
+            _ => None,
+        }
+    }
+
+    /// Number of paging structures mapped below the vspace root through
+    /// caps, i.e. not counting those added with add_paging_structure().
+    pub fn num_paging_structures(&self) -> usize {
+        self.slots
+            .values()
+            .filter(|cap| match self.objects[cap.object].kind {
+                ObjectKind::PagingStructure {
+                    depth: Some(depth), ..
+                } => depth > 0,
+                _ => false,
+            })
+            .count()
+    }
+
+    fn vspace_root(&self) -> usize {
+        self.slots[&SIM_VSPACE].object
+    }
+
+    fn entries(&self, table: usize) -> &BTreeMap<seL4_Word, Entry> {
+        match self.objects[table].kind {
+            ObjectKind::PagingStructure { ref entries, .. } => entries,
+            _ => unreachable!(),
+        }
+    }
+
+    fn entries_mut(&mut self, table: usize) -> &mut BTreeMap<seL4_Word, Entry> {
+        match self.objects[table].kind {
+            ObjectKind::PagingStructure {
+                ref mut entries, ..
+            } => entries,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Walk from 'root' down to the paging structure at 'depth' covering
+    /// 'vaddr'.
+    fn walk(&self, root: usize, vaddr: seL4_Word, depth: usize) -> Result<usize, WalkError> {
+        let mut table = root;
+        for d in 0..depth {
+            match self.entries(table).get(&(vaddr >> entry_bits(d))) {
+                Some(Entry::PagingStructure(next)) => table = *next,
+                Some(Entry::Frame(_)) => return Err(WalkError::Frame),
+                None => return Err(WalkError::Missing(d + 1)),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Record the paging structure missing at 'depth' for
+    /// seL4_MappingFailedLookupLevel()
+    fn failed_lookup(&mut self, depth: usize) -> seL4_Error {
+        self.failed_lookup_level = PAGING_LEVELS[depth - 1].vaddr_bits as _;
+        seL4_Error_seL4_FailedLookup
+    }
+
+    fn insert_object(&mut self, slot: seL4_CPtr, parent: Option<seL4_CPtr>, object: Object) {
+        self.objects.push(object);
+        let cap = Cap {
+            object: self.objects.len() - 1,
+            parent,
+        };
+        self.slots.insert(slot, cap);
+    }
+
+    fn has_children(&self, slot: seL4_CPtr) -> bool {
+        self.slots.values().any(|cap| cap.parent == Some(slot))
+    }
+
+    /// Delete the cap in 'slot', destroying the object if it was the last
+    /// cap to it.
+    fn delete_slot(&mut self, slot: seL4_CPtr) {
+        let cap = match self.slots.remove(&slot) {
+            Some(cap) => cap,
+            None => return,
+        };
+
+        // Any children outlive us, but now derive from our parent
+        for child in self.slots.values_mut() {
+            if child.parent == Some(slot) {
+                child.parent = cap.parent;
+            }
+        }
+
+        if !self.slots.values().any(|c| c.object == cap.object) {
+            self.destroy_object(cap.object);
+        }
+    }
+
+    /// Remove all traces of an object from the paging structures.
+    fn destroy_object(&mut self, object: usize) {
+        match self.objects[object].kind.clone() {
+            ObjectKind::Frame { mapped: Some(_) } => self.unmap_frame(object),
+            ObjectKind::PagingStructure {
+                depth: Some(depth), ..
+            } if depth > 0 => self.unmap_paging_structure(object),
+            _ => (),
+        }
+
+        self.objects[object].kind = ObjectKind::Other;
+    }
+
+    /// Remove a frame from the paging structures it is mapped into.
+    fn unmap_frame(&mut self, frame: usize) {
+        for obj in self.objects.iter_mut() {
+            if let ObjectKind::PagingStructure {
+                ref mut entries, ..
+            } = obj.kind
+            {
+                entries.retain(|_, e| *e != Entry::Frame(frame));
+            }
+        }
+
+        self.objects[frame].kind = ObjectKind::Frame { mapped: None };
+        self.frame_attributes.remove(&frame);
+    }
+
+    /// Remove a paging structure from the one above it. The kernel clears
+    /// it, the frames and structures that were mapped into it keep
+    /// thinking they are.
+    fn unmap_paging_structure(&mut self, table: usize) {
+        for obj in self.objects.iter_mut() {
+            if let ObjectKind::PagingStructure {
+                ref mut entries, ..
+            } = obj.kind
+            {
+                entries.retain(|_, e| *e != Entry::PagingStructure(table));
+            }
+        }
+
+        self.objects[table].kind = ObjectKind::PagingStructure {
+            depth: None,
+            entries: BTreeMap::new(),
+        };
+    }
+
+    fn lookup(&self, slot: seL4_CPtr) -> Result<usize, seL4_Error> {
+        self.slots
+            .get(&slot)
+            .map(|cap| cap.object)
+            .ok_or(seL4_Error_seL4_FailedLookup)
+    }
+
+    /// The vspace root the cap in 'slot' refers to
+    fn lookup_vspace(&self, slot: seL4_CPtr) -> Result<usize, seL4_Error> {
+        let root = self
+            .lookup(slot)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+        match self.objects[root].kind {
+            ObjectKind::PagingStructure { depth: Some(0), .. } => Ok(root),
+            _ => Err(seL4_Error_seL4_InvalidCapability),
+        }
+    }
+}
+
+/// Bits of virtual address space each entry of a paging structure at
+/// 'depth' below the vspace root covers
+fn entry_bits(depth: usize) -> usize {
+    PAGING_LEVELS
+        .get(depth)
+        .map_or(seL4_PageBits as usize, |level| level.vaddr_bits)
+}
+
+/// Depth of the paging structure a frame of 'frame_bits' is mapped into,
+/// the highest one whose entries aren't bigger than the frame
+fn frame_depth(frame_bits: usize) -> usize {
+    (0..=PAGING_LEVELS.len())
+        .find(|&depth| entry_bits(depth) <= frame_bits)
+        .unwrap()
+}
+
+/// Size (in bits) of an object of the given type, as the kernel sees it.
+fn object_size_bits(item_type: seL4_Word, size_bits: usize) -> Result<usize, seL4_Error> {
+    let obj_type = ObjectType::from_sel4(item_type).ok_or(seL4_Error_seL4_InvalidArgument)?;
+
+    if obj_type == ObjectType::Untyped {
+        if size_bits < seL4_MinUntypedBits as usize || size_bits > seL4_MaxUntypedBits as usize {
+            return Err(seL4_Error_seL4_RangeError);
+        }
+        return Ok(size_bits);
+    }
+
+    obj_type
+        .size_bits(size_bits)
+        .map_err(|_| seL4_Error_seL4_RangeError)
+}
+
+fn is_frame_type(obj_type: ObjectType) -> bool {
+    FRAME_SIZES
+        .iter()
+        .any(|&bits| ObjectType::frame(bits) == Ok(obj_type))
+}
+
+fn is_paging_structure_type(obj_type: ObjectType) -> bool {
+    obj_type == VSPACE_ROOT || PAGING_LEVELS.iter().any(|level| level.obj_type == obj_type)
+}
+
+fn from_result(res: Result<(), seL4_Error>) -> seL4_Error {
+    match res {
+        Ok(()) => seL4_Error_seL4_NoError,
+        Err(e) => e,
+    }
+}
+
+impl SimKernel {
+    /// A lookup in the CNode at any other depth doesn't resolve
+    fn check_depth(&self, depth: seL4_Word) -> Result<(), seL4_Error> {
+        if depth != self.cnode_depth {
+            return Err(seL4_Error_seL4_FailedLookup);
+        }
+        Ok(())
+    }
+
+    fn do_retype(
+        &mut self,
+        service: seL4_CPtr,
+        item_type: seL4_Word,
+        size_bits: seL4_Word,
+        dest: &CSpacePath,
+        num_objects: seL4_Word,
+    ) -> Result<(), seL4_Error> {
+        let node_offset = dest.offset;
+        let ut = self
+            .lookup(service)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+        let (is_device, watermark) = match self.objects[ut].kind {
+            ObjectKind::Untyped {
+                is_device,
+                watermark,
+            } => (is_device, watermark),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        };
+
+        let obj_bits = object_size_bits(item_type, size_bits as _)?;
+        let obj_type = ObjectType::from_sel4(item_type).unwrap();
+        match self.retype_error {
+            Some((failing_type, err)) if failing_type == item_type => return Err(err),
+            _ => (),
+        }
+
+        if !(1..=RETYPE_FAN_OUT_LIMIT).contains(&num_objects) {
+            return Err(seL4_Error_seL4_RangeError);
+        }
+
+        // Resolve the destination CNode
+        let cnode = if dest.dest_depth == 0 {
+            self.lookup(dest.root)?
+        } else {
+            self.check_depth(dest.dest_depth)?;
+            self.lookup(dest.dest)?
+        };
+        match self.objects[cnode].kind {
+            ObjectKind::CNode => (),
+            _ => return Err(seL4_Error_seL4_FailedLookup),
+        }
+
+        if node_offset + num_objects > SIM_CNODE_SLOTS as seL4_Word {
+            return Err(seL4_Error_seL4_RangeError);
+        }
+        for slot in node_offset..(node_offset + num_objects) {
+            if self.slots.contains_key(&slot) {
+                return Err(seL4_Error_seL4_DeleteFirst);
+            }
+        }
+
+        // Device memory can only be retyped into frames or more untyped
+        if is_device && !is_frame_type(obj_type) && obj_type != ObjectType::Untyped {
+            return Err(seL4_Error_seL4_InvalidArgument);
+        }
+
+        // An untyped without children is reset before use
+        let watermark = if self.has_children(service) {
+            watermark
+        } else {
+            0
+        };
+
+        let obj_size: seL4_Word = 1 << obj_bits;
+        let start = (watermark + obj_size - 1) & !(obj_size - 1);
+        let end = start + (num_objects * obj_size);
+        if end > (1 << self.objects[ut].size_bits) {
+            return Err(seL4_Error_seL4_NotEnoughMemory);
+        }
+
+        if let ObjectKind::Untyped {
+            ref mut watermark, ..
+        } = self.objects[ut].kind
+        {
+            *watermark = end;
+        }
+
+        let base_paddr = self.objects[ut].paddr;
+        for i in 0..num_objects {
+            let kind = match obj_type {
+                ObjectType::Untyped => ObjectKind::Untyped {
+                    is_device,
+                    watermark: 0,
+                },
+                ObjectType::CapTable => ObjectKind::CNode,
+                #[cfg(feature = "mcs")]
+                ObjectType::SchedContext => ObjectKind::SchedContext { params: None },
+                t if is_frame_type(t) => ObjectKind::Frame { mapped: None },
+                t if is_paging_structure_type(t) => ObjectKind::PagingStructure {
+                    depth: None,
+                    entries: BTreeMap::new(),
+                },
+                _ => ObjectKind::Other,
+            };
+
+            self.insert_object(
+                node_offset + i,
+                Some(service),
+                Object {
+                    item_type,
+                    paddr: base_paddr + start + (i * obj_size),
+                    size_bits: obj_bits,
+                    kind,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn do_page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> Result<(), seL4_Error> {
+        let frame = self
+            .lookup(page)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+        let root = self.lookup_vspace(vspace)?;
+
+        match self.objects[frame].kind {
+            ObjectKind::Frame { mapped: None } => (),
+            ObjectKind::Frame { mapped: Some(_) } => return Err(seL4_Error_seL4_InvalidArgument),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        }
+
+        let frame_bits = self.objects[frame].size_bits;
+        if vaddr & ((1 << frame_bits) - 1) != 0 {
+            return Err(seL4_Error_seL4_AlignmentError);
+        }
+        if vaddr + (1 << frame_bits) > KERNEL_BASE {
+            return Err(seL4_Error_seL4_InvalidArgument);
+        }
+
+        // Frames bigger than the entries they go in take up several
+        let depth = frame_depth(frame_bits);
+        let table = match self.walk(root, vaddr, depth) {
+            Ok(table) => table,
+            Err(WalkError::Missing(missing)) => return Err(self.failed_lookup(missing)),
+            Err(WalkError::Frame) => return Err(seL4_Error_seL4_DeleteFirst),
+        };
+        if self.page_map_error != seL4_Error_seL4_NoError {
+            return Err(self.page_map_error);
+        }
+
+        let first = vaddr >> entry_bits(depth);
+        let count = 1 << (frame_bits - entry_bits(depth));
+        let entries = self.entries_mut(table);
+        if (first..first + count).any(|i| entries.contains_key(&i)) {
+            return Err(seL4_Error_seL4_DeleteFirst);
+        }
+        for i in first..first + count {
+            entries.insert(i, Entry::Frame(frame));
+        }
+
+        self.objects[frame].kind = ObjectKind::Frame {
+            mapped: Some(vaddr),
+        };
+        self.frame_attributes.insert(frame, (rights.words[0], attr));
+
+        Ok(())
+    }
+
+    fn do_page_unmap(&mut self, page: seL4_CPtr) -> Result<(), seL4_Error> {
+        let frame = self
+            .lookup(page)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+
+        match self.objects[frame].kind {
+            ObjectKind::Frame { mapped: Some(_) } => self.unmap_frame(frame),
+            // Unmapping a frame that isn't mapped does nothing
+            ObjectKind::Frame { mapped: None } => (),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        }
+
+        Ok(())
+    }
+
+    fn do_paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+    ) -> Result<(), seL4_Error> {
+        let table = self
+            .lookup(service)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+        let root = self.lookup_vspace(vspace)?;
+
+        if self.objects[table].item_type != obj_type.to_sel4() {
+            return Err(seL4_Error_seL4_InvalidCapability);
+        }
+        match self.objects[table].kind {
+            ObjectKind::PagingStructure { depth: None, .. } => (),
+            ObjectKind::PagingStructure { .. } => return Err(seL4_Error_seL4_InvalidArgument),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        }
+
+        if vaddr >= KERNEL_BASE {
+            return Err(seL4_Error_seL4_InvalidArgument);
+        }
+
+        let depths: Vec<usize> = (1..=PAGING_LEVELS.len())
+            .filter(|&depth| PAGING_LEVELS[depth - 1].obj_type == obj_type)
+            .collect();
+        let depth = match depths.len() {
+            0 => return Err(seL4_Error_seL4_InvalidCapability),
+            1 => depths[0],
+            // Structures of one type at several levels (RISC-V page
+            // tables) go in at the first level that is missing
+            _ => match self.walk(root, vaddr, PAGING_LEVELS.len()) {
+                Err(WalkError::Missing(depth)) if depths.contains(&depth) => depth,
+                _ => return Err(seL4_Error_seL4_DeleteFirst),
+            },
+        };
+
+        let parent = match self.walk(root, vaddr, depth - 1) {
+            Ok(parent) => parent,
+            Err(WalkError::Missing(missing)) => return Err(self.failed_lookup(missing)),
+            Err(WalkError::Frame) => return Err(seL4_Error_seL4_DeleteFirst),
+        };
+        let index = vaddr >> entry_bits(depth - 1);
+        let entries = self.entries_mut(parent);
+        if entries.contains_key(&index) {
+            return Err(seL4_Error_seL4_DeleteFirst);
+        }
+        entries.insert(index, Entry::PagingStructure(table));
+
+        if let ObjectKind::PagingStructure {
+            depth: ref mut d, ..
+        } = self.objects[table].kind
+        {
+            *d = Some(depth);
+        }
+
+        Ok(())
+    }
+
+    fn do_paging_structure_unmap(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+    ) -> Result<(), seL4_Error> {
+        let table = self
+            .lookup(service)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+
+        if self.objects[table].item_type != obj_type.to_sel4() {
+            return Err(seL4_Error_seL4_InvalidCapability);
+        }
+        match self.objects[table].kind {
+            ObjectKind::PagingStructure {
+                depth: Some(depth), ..
+            } if depth > 0 => self.unmap_paging_structure(table),
+            // Unmapping a structure that isn't mapped does nothing
+            ObjectKind::PagingStructure { depth: None, .. } => (),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        }
+
+        Ok(())
+    }
+}
+
+impl SimKernel {
+    #[cfg(feature = "mcs")]
+    fn do_sched_control_configure(
+        &mut self,
+        service: seL4_CPtr,
+        sched_context: seL4_CPtr,
+        budget: seL4_Time,
+        period: seL4_Time,
+    ) -> Result<(), seL4_Error> {
+        if !(SIM_SCHED_CONTROL..(SIM_SCHED_CONTROL + SIM_NUM_NODES)).contains(&service) {
+            return Err(seL4_Error_seL4_InvalidCapability);
+        }
+        if budget > period {
+            return Err(seL4_Error_seL4_RangeError);
+        }
+
+        let sc = self
+            .lookup(sched_context)
+            .map_err(|_| seL4_Error_seL4_InvalidCapability)?;
+        match self.objects[sc].kind {
+            ObjectKind::SchedContext { ref mut params } => *params = Some((budget, period)),
+            _ => return Err(seL4_Error_seL4_InvalidCapability),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn do_ioport_issue(
+        &mut self,
+        service: seL4_CPtr,
+        first_port: seL4_Word,
+        last_port: seL4_Word,
+        index: seL4_Word,
+        depth: u8,
+    ) -> Result<(), seL4_Error> {
+        if service != seL4_CapIOPortControl {
+            return Err(seL4_Error_seL4_InvalidCapability);
+        }
+        if first_port > last_port {
+            return Err(seL4_Error_seL4_RangeError);
+        }
+        self.check_depth(depth as _)?;
+        if self.slots.contains_key(&index) {
+            return Err(seL4_Error_seL4_DeleteFirst);
+        }
+
+        // Every port can only be issued once
+        let in_use = self
+            .slots
+            .values()
+            .any(|cap| match self.objects[cap.object].kind {
+                ObjectKind::IoPort { first, last } => (first_port <= last) && (first <= last_port),
+                _ => false,
+            });
+        if in_use {
+            return Err(seL4_Error_seL4_RevokeFirst);
+        }
+
+        self.insert_object(
+            index,
+            None,
+            Object {
+                item_type: 0,
+                paddr: 0,
+                size_bits: 0,
+                kind: ObjectKind::IoPort {
+                    first: first_port,
+                    last: last_port,
+                },
+            },
+        );
+
+        Ok(())
+    }
+
+    fn do_delete(&mut self, service: seL4_CPtr, index: seL4_Word) -> Result<(), seL4_Error> {
+        match self.objects[self.lookup(service)?].kind {
+            ObjectKind::CNode => (),
+            _ => return Err(seL4_Error_seL4_FailedLookup),
+        }
+
+        // Deleting an empty slot is fine
+        self.delete_slot(index);
+
+        Ok(())
+    }
+
+    fn do_revoke(&mut self, service: seL4_CPtr, index: seL4_Word) -> Result<(), seL4_Error> {
+        match self.objects[self.lookup(service)?].kind {
+            ObjectKind::CNode => (),
+            _ => return Err(seL4_Error_seL4_FailedLookup),
+        }
+        self.lookup(index)?;
+
+        // Collect all descendants, then delete the deepest ones first
+        let mut descendants: Vec<seL4_CPtr> = vec![index];
+        let mut i = 0;
+        while i < descendants.len() {
+            let parent = descendants[i];
+            for (slot, cap) in self.slots.iter() {
+                if cap.parent == Some(parent) {
+                    descendants.push(*slot);
+                }
+            }
+            i += 1;
+        }
+
+        for slot in descendants.iter().skip(1).rev() {
+            self.delete_slot(*slot);
+        }
+
+        Ok(())
+    }
+}
+
+impl Kernel for SimKernel {
+    fn untyped_retype(
+        &mut self,
+        service: seL4_CPtr,
+        item_type: seL4_Word,
+        size_bits: seL4_Word,
+        dest: &CSpacePath,
+        num_objects: seL4_Word,
+    ) -> seL4_Error {
+        from_result(self.do_retype(service, item_type, size_bits, dest, num_objects))
+    }
+
+    fn page_map(
+        &mut self,
+        page: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        rights: seL4_CapRights,
+        attr: VmAttributes,
+    ) -> seL4_Error {
+        from_result(self.do_page_map(page, vspace, vaddr, rights, attr))
+    }
+
+    fn page_unmap(&mut self, page: seL4_CPtr) -> seL4_Error {
+        from_result(self.do_page_unmap(page))
+    }
+
+    fn mapping_failed_lookup_level(&self) -> seL4_Word {
+        self.failed_lookup_level
+    }
+
+    fn paging_structure_map(
+        &mut self,
+        obj_type: ObjectType,
+        service: seL4_CPtr,
+        vspace: seL4_CPtr,
+        vaddr: seL4_Word,
+        _attr: VmAttributes,
+    ) -> seL4_Error {
+        from_result(self.do_paging_structure_map(obj_type, service, vspace, vaddr))
+    }
+
+    fn paging_structure_unmap(&mut self, obj_type: ObjectType, service: seL4_CPtr) -> seL4_Error {
+        from_result(self.do_paging_structure_unmap(obj_type, service))
+    }
+
+    fn cnode_delete(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error {
+        from_result(
+            self.check_depth(depth as _)
+                .and_then(|_| self.do_delete(service, index)),
+        )
+    }
+
+    fn cnode_revoke(&mut self, service: seL4_CPtr, index: seL4_Word, depth: u8) -> seL4_Error {
+        from_result(
+            self.check_depth(depth as _)
+                .and_then(|_| self.do_revoke(service, index)),
+        )
+    }
+
+    #[cfg(feature = "mcs")]
+    fn sched_control_configure(
+        &mut self,
+        service: seL4_CPtr,
+        sched_context: seL4_CPtr,
+        budget: seL4_Time,
+        period: seL4_Time,
+        _extra_refills: seL4_Word,
+        _badge: seL4_Word,
+    ) -> seL4_Error {
+        from_result(self.do_sched_control_configure(service, sched_context, budget, period))
+    }
+
+    #[cfg(feature = "x86_64")]
+    fn ioport_control_issue(
+        &mut self,
+        service: seL4_CPtr,
+        first_port: seL4_Word,
+        last_port: seL4_Word,
+        _root: seL4_CPtr,
+        index: seL4_Word,
+        depth: u8,
+    ) -> seL4_Error {
+        from_result(self.do_ioport_issue(service, first_port, last_port, index, depth))
+    }
+}
+
+/// An untyped handed to 'allocator()': (paddr, size_bits, is_device)
+pub type SimUntyped = (seL4_Word, usize, bool);
+
+/// Build an allocator on top of a fresh simulated kernel, the same way
+/// `bootstrap()` does from bootinfo.
+///
+/// The untypeds are placed in the slots just below SIM_FIRST_FREE_SLOT.
+pub fn allocator(untypeds: &[SimUntyped]) -> Allocator<SimKernel> {
+    allocator_with_cnode_depth(untypeds, seL4_WordBits as _)
+}
+
+/// As allocator(), with the CNode resolved at 'cnode_depth' bits.
+pub fn allocator_with_cnode_depth(
+    untypeds: &[SimUntyped],
+    cnode_depth: seL4_Word,
+) -> Allocator<SimKernel> {
+    let mut kernel = SimKernel::new();
+    kernel.set_cnode_depth(cnode_depth);
+    let first_ut = SIM_FIRST_FREE_SLOT - untypeds.len() as seL4_CPtr;
+    for (i, ut) in untypeds.iter().enumerate() {
+        kernel.add_untyped(first_ut + i as seL4_CPtr, ut.0, ut.1, ut.2);
+    }
+
+    let mut alloc = Allocator::with_kernel(kernel);
+    alloc.create(
+        SIM_CNODE,
+        cnode_depth as _,
+        0,
+        SIM_FIRST_FREE_SLOT as _,
+        SIM_CNODE_SLOTS - SIM_FIRST_FREE_SLOT as usize,
+        &[],
+    );
+    for (i, ut) in untypeds.iter().enumerate() {
+        alloc.add_root_untyped_item(first_ut + i as seL4_CPtr, ut.1, ut.0, ut.2);
+    }
+    alloc
+        .bootstrap_vspace(SIM_VSPACE)
+        .expect("failed to bootstrap vspace");
+
+    alloc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arch::DEFAULT_VM_ATTRIBUTES;
+
+    /// Path to 'slot' in the CNode
+    fn slot(slot: seL4_CPtr) -> CSpacePath {
+        CSpacePath {
+            cap_ptr: slot,
+            cap_depth: seL4_WordBits as _,
+            root: SIM_CNODE,
+            dest: SIM_CNODE,
+            dest_depth: seL4_WordBits as _,
+            offset: slot,
+            window: 1,
+        }
+    }
+
+    #[test]
+    fn retype_rejects_occupied_slot() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x1000_0000, 12, false);
+        assert_eq!(
+            kernel.untyped_retype(10, api_object_seL4_EndpointObject, 0, &slot(20), 1),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(
+            kernel.untyped_retype(10, api_object_seL4_EndpointObject, 0, &slot(20), 1),
+            seL4_Error_seL4_DeleteFirst
+        );
+    }
+
+    #[test]
+    fn retype_respects_watermark() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x1000_0000, 12, false);
+
+        // Two 2K halves fit, a third does not
+        assert_eq!(
+            kernel.untyped_retype(10, api_object_seL4_UntypedObject, 11, &slot(20), 2),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(kernel.cap_paddr(21), Some(0x1000_0800));
+        assert_eq!(
+            kernel.untyped_retype(10, api_object_seL4_UntypedObject, 11, &slot(22), 1),
+            seL4_Error_seL4_NotEnoughMemory
+        );
+
+        // Too big for the untyped at all
+        assert_eq!(
+            kernel.untyped_retype(21, api_object_seL4_UntypedObject, 12, &slot(22), 1),
+            seL4_Error_seL4_NotEnoughMemory
+        );
+    }
+
+    #[test]
+    fn retype_rejects_device_kernel_objects() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x0200_0000, 12, true);
+        assert_eq!(
+            kernel.untyped_retype(10, api_object_seL4_TCBObject, 0, &slot(20), 1),
+            seL4_Error_seL4_InvalidArgument
+        );
+        assert_eq!(
+            kernel.untyped_retype(10, ObjectType::SmallPage.to_sel4(), 0, &slot(20), 1),
+            seL4_Error_seL4_NoError
+        );
+    }
+
+    #[test]
+    fn page_map_reports_the_missing_level_from_the_top_down() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x1000_0000, 16, false);
+        assert_eq!(
+            kernel.untyped_retype(10, ObjectType::SmallPage.to_sel4(), 0, &slot(20), 1),
+            seL4_Error_seL4_NoError
+        );
+
+        let rights = || unsafe { seL4_CapRights_new(1, 1, 1) };
+        let attr = DEFAULT_VM_ATTRIBUTES;
+        let vaddr = 0x2000_1000;
+        for (i, level) in PAGING_LEVELS.iter().enumerate() {
+            assert_eq!(
+                kernel.page_map(20, SIM_VSPACE, vaddr, rights(), attr),
+                seL4_Error_seL4_FailedLookup
+            );
+            assert_eq!(
+                kernel.mapping_failed_lookup_level(),
+                level.vaddr_bits as seL4_Word
+            );
+
+            let cap = 21 + i as seL4_CPtr;
+            assert_eq!(
+                kernel.untyped_retype(10, level.obj_type.to_sel4(), 0, &slot(cap), 1),
+                seL4_Error_seL4_NoError
+            );
+            assert_eq!(
+                kernel.paging_structure_map(level.obj_type, cap, SIM_VSPACE, vaddr, attr),
+                seL4_Error_seL4_NoError
+            );
+            assert!(kernel.has_paging_structure(i, vaddr));
+        }
+
+        assert_eq!(
+            kernel.page_map(20, SIM_VSPACE, vaddr, rights(), attr),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(kernel.translate(vaddr + 0x234), Some(0x1000_0000 + 0x234));
+        assert_eq!(kernel.num_paging_structures(), PAGING_LEVELS.len());
+    }
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn riscv_page_tables_go_in_at_the_first_level_missing() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x1000_0000, 16, false);
+        let attr = DEFAULT_VM_ATTRIBUTES;
+        let vaddr = 0x2000_1000;
+
+        for level in 0..PAGING_LEVELS.len() {
+            let cap = 20 + level as seL4_CPtr;
+            assert_eq!(
+                kernel.untyped_retype(10, ObjectType::PageTable.to_sel4(), 0, &slot(cap), 1),
+                seL4_Error_seL4_NoError
+            );
+            assert_eq!(
+                kernel.paging_structure_map(ObjectType::PageTable, cap, SIM_VSPACE, vaddr, attr),
+                seL4_Error_seL4_NoError
+            );
+            assert!(kernel.has_paging_structure(level, vaddr));
+        }
+
+        // Nowhere left to put another
+        assert_eq!(
+            kernel.untyped_retype(10, ObjectType::PageTable.to_sel4(), 0, &slot(30), 1),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(
+            kernel.paging_structure_map(ObjectType::PageTable, 30, SIM_VSPACE, vaddr, attr),
+            seL4_Error_seL4_DeleteFirst
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "aarch32")]
+    fn page_map_requires_page_table() {
+        let mut kernel = SimKernel::new();
+        kernel.add_untyped(10, 0x1000_0000, 16, false);
+        assert_eq!(
+            kernel.untyped_retype(10, _object_seL4_ARM_SmallPageObject, 0, &slot(20), 1),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(
+            kernel.untyped_retype(10, _object_seL4_ARM_PageTableObject, 0, &slot(21), 1),
+            seL4_Error_seL4_NoError
+        );
+
+        let rights = || unsafe { seL4_CapRights_new(1, 1, 1) };
+        let attr = seL4_ARM_VMAttributes_seL4_ARM_Default_VMAttributes;
+        assert_eq!(
+            kernel.page_map(20, SIM_VSPACE, 0x2000_0000, rights(), attr),
+            seL4_Error_seL4_FailedLookup
+        );
+        assert_eq!(
+            kernel.paging_structure_map(ObjectType::PageTable, 21, SIM_VSPACE, 0x2000_0000, attr),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(
+            kernel.page_map(20, SIM_VSPACE, 0x2000_1001, rights(), attr),
+            seL4_Error_seL4_AlignmentError
+        );
+        assert_eq!(
+            kernel.page_map(20, SIM_VSPACE, 0x2000_1000, rights(), attr),
+            seL4_Error_seL4_NoError
+        );
+        assert_eq!(kernel.translate(0x2000_1234), Some(0x1000_0000 + 0x234));
+    }
+}
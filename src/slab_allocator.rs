@@ -0,0 +1,334 @@
+/// Slab allocator for small kernel objects.
+///
+/// Rather than an untyped of its own per object, a slab retypes one
+/// untyped (a page by default) into as many object sized untypeds as fit,
+/// and retypes each object from one of those. Freed objects are destroyed
+/// rather than handed out again in whatever state they were left, which
+/// leaves their untyped without children so the kernel resets it and the
+/// next object can be retyped fresh in its place. The slab's untyped goes
+/// back to the allocator once none of its objects are in use.
+use super::{
+    Allocator, CapRange, Error, Kernel, ObjectType, Operation, Slab, Vka, MAX_SLAB_OBJECTS,
+    MAX_UNTYPED_SIZE, MIN_UNTYPED_SIZE,
+};
+use core::cmp;
+use sel4_sys::seL4_CPtr;
+
+impl Slab {
+    pub(crate) const fn unused() -> Self {
+        Slab {
+            item_type: None,
+            ut: 0,
+            uts: CapRange { first: 0, count: 0 },
+            caps: CapRange { first: 0, count: 0 },
+            num_used: 0,
+            used_bitmap: [0; MAX_SLAB_OBJECTS / 32],
+        }
+    }
+
+    /// Index of 'cap' in this slab
+    fn index_of(&self, cap: seL4_CPtr) -> Option<usize> {
+        if self.item_type.is_none() || (cap < self.caps.first()) {
+            return None;
+        }
+
+        let index = (cap - self.caps.first()) as usize;
+        if index < self.caps.count() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        (self.used_bitmap[index / 32] & (1 << (index % 32))) != 0
+    }
+
+    /// Index of an object that isn't handed out
+    fn free_index(&self) -> Option<usize> {
+        (0..self.caps.count()).find(|&index| !self.is_used(index))
+    }
+}
+
+impl<K: Kernel> Allocator<K> {
+    /// Set the size (in bits) of the untyped new slabs are retyped from.
+    ///
+    /// Slabs that already exist keep their size.
+    pub fn set_slab_size_bits(&mut self, size_bits: usize) -> Result<(), Error> {
+        if !(MIN_UNTYPED_SIZE..=MAX_UNTYPED_SIZE).contains(&size_bits) {
+            return Err(Error::SizeOutOfRange(size_bits));
+        }
+
+        self.slab_size_bits = size_bits;
+        Ok(())
+    }
+
+    /// Allocate an object of a fixed size type (endpoint, notification, TCB,
+    /// ...) from a slab, creating a new slab if none of them have room.
+    pub fn slab_alloc(&mut self, item_type: ObjectType) -> Result<seL4_CPtr, Error> {
+        let slab = match self
+            .slabs
+            .iter()
+            .position(|s| (s.item_type == Some(item_type)) && (s.num_used < s.caps.count()))
+        {
+            Some(slab) => slab,
+            None => self.new_slab(item_type)?,
+        };
+
+        let index = self.slabs[slab].free_index().ok_or(Error::SlabsExhausted)?;
+        let ut = self.slabs[slab].uts.first() + index as seL4_CPtr;
+        let cap = CapRange {
+            first: self.slabs[slab].caps.first + index,
+            count: 1,
+        };
+
+        if let Err(e) = self.retype_untyped_memory_into(ut, item_type, 0, &cap) {
+            // Don't keep a slab we couldn't use
+            if self.slabs[slab].num_used == 0 {
+                self.release_slab(slab)?;
+            }
+            return Err(e);
+        }
+
+        let slab = &mut self.slabs[slab];
+        slab.num_used += 1;
+        slab.used_bitmap[index / 32] |= 1 << (index % 32);
+
+        Ok(cap.first())
+    }
+
+    /// Give an object from slab_alloc() back to its slab.
+    ///
+    /// The object is destroyed along with any caps derived from it, e.g. a
+    /// TCB is stopped and threads waiting on an endpoint are released. Its
+    /// slab is freed once none of its objects are left.
+    pub fn slab_free(&mut self, cap: seL4_CPtr) -> Result<(), Error> {
+        let (slab, index) = self
+            .slabs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.index_of(cap).map(|index| (i, index)))
+            .next()
+            .ok_or(Error::UnknownSlabObject(cap))?;
+
+        if !self.slabs[slab].is_used(index) {
+            return Err(Error::UnknownSlabObject(cap));
+        }
+
+        // Revoking its untyped deletes the object cap and everything
+        // derived from it, the untyped can then be retyped again
+        let ut = self.slabs[slab].uts.first() + index as seL4_CPtr;
+        let path = self.vka_cspace_make_path(ut);
+        let err = self
+            .kernel
+            .cnode_revoke(path.root, path.cap_ptr, path.cap_depth as _);
+        Error::check(Operation::CNodeRevoke, err)?;
+
+        {
+            let slab = &mut self.slabs[slab];
+            slab.used_bitmap[index / 32] &= !(1 << (index % 32));
+            slab.num_used -= 1;
+        }
+
+        if self.slabs[slab].num_used == 0 {
+            self.release_slab(slab)?;
+        }
+
+        Ok(())
+    }
+
+    /// Retype a new slab of 'item_type' objects.
+    fn new_slab(&mut self, item_type: ObjectType) -> Result<usize, Error> {
+        let index = self
+            .slabs
+            .iter()
+            .position(|s| s.item_type.is_none())
+            .ok_or(Error::SlabsExhausted)?;
+
+        // Only objects with a fixed size, the others don't have one for 0
+        let obj_size_bits = self.vka_get_object_size(item_type, 0)?;
+        let ut_size_bits = cmp::max(obj_size_bits, MIN_UNTYPED_SIZE);
+        let count_bits = self.slab_size_bits.saturating_sub(ut_size_bits);
+        let num_objects = cmp::min(1 << count_bits, MAX_SLAB_OBJECTS);
+
+        let (ut, uts) = self.alloc_kobjects(ObjectType::Untyped, ut_size_bits, num_objects)?;
+        let caps = match self.alloc_cslot_range(num_objects) {
+            Ok(caps) => caps,
+            Err(e) => {
+                let _ = self.free_untyped(ut);
+                self.free_cslot_range(&uts);
+                return Err(e);
+            }
+        };
+
+        let slab = &mut self.slabs[index];
+        *slab = Slab::unused();
+        slab.item_type = Some(item_type);
+        slab.ut = ut;
+        slab.uts = uts;
+        slab.caps = caps;
+
+        Ok(index)
+    }
+
+    /// Free a slab with none of its objects in use.
+    fn release_slab(&mut self, index: usize) -> Result<(), Error> {
+        let slab = self.slabs[index];
+
+        self.free_untyped(slab.ut)?;
+        self.free_cslot_range(&slab.uts);
+        self.free_cslot_range(&slab.caps);
+        self.slabs[index] = Slab::unused();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sel4_sys::*;
+    use sim;
+    use std::vec::Vec;
+    use MAX_SLABS;
+
+    #[test]
+    fn slab_alloc_fills_a_page_of_objects_from_one_untyped() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        let per_slab = 1 << (12 - seL4_EndpointBits);
+
+        let slab_ut = |alloc: &Allocator<sim::SimKernel>, cap| {
+            let ut = alloc.kernel.cap_parent(cap).unwrap();
+            assert_eq!(alloc.kernel.cap_size_bits(ut), Some(seL4_EndpointBits as _));
+            alloc.kernel.cap_parent(ut).unwrap()
+        };
+
+        let first = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+        let ut = slab_ut(&alloc, first);
+        assert_eq!(alloc.kernel.cap_size_bits(ut), Some(12));
+
+        for i in 1..per_slab {
+            let ep = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+            assert_eq!(ep, first + i as seL4_CPtr);
+            assert_eq!(slab_ut(&alloc, ep), ut);
+        }
+
+        // The next one needs a new slab
+        let ep = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+        assert_ne!(slab_ut(&alloc, ep), ut);
+    }
+
+    #[test]
+    fn slab_free_destroys_the_object() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        let a = alloc.slab_alloc(ObjectType::Notification).unwrap();
+        let b = alloc.slab_alloc(ObjectType::Notification).unwrap();
+        alloc.slab_free(a).unwrap();
+        assert_eq!(alloc.kernel.cap_type(a), None);
+        assert_eq!(alloc.slab_free(a), Err(Error::UnknownSlabObject(a)));
+
+        // Retyped fresh in the same slot
+        let c = alloc.slab_alloc(ObjectType::Notification).unwrap();
+        assert_eq!(c, a);
+        assert_eq!(
+            alloc.kernel.cap_type(c),
+            Some(api_object_seL4_NotificationObject)
+        );
+
+        // Slabs are per type
+        let ep = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+        let slab_ut = |cap| {
+            let ut = alloc.kernel.cap_parent(cap).unwrap();
+            alloc.kernel.cap_parent(ut)
+        };
+        assert_ne!(slab_ut(ep), slab_ut(b));
+    }
+
+    #[test]
+    fn slabs_are_freed_once_their_objects_are() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        alloc.set_slab_size_bits(8).unwrap();
+        let per_slab = 1 << (8 - seL4_EndpointBits);
+        let num_slots = alloc.num_slots_used;
+
+        let eps: Vec<_> = (0..per_slab)
+            .map(|_| alloc.slab_alloc(ObjectType::Endpoint).unwrap())
+            .collect();
+        for &ep in eps.iter() {
+            alloc.slab_free(ep).unwrap();
+        }
+
+        assert_eq!(alloc.num_slots_used, num_slots);
+        assert!(alloc.slabs.iter().all(|s| s.item_type.is_none()));
+        assert!(alloc.alloc_untyped(16, None, false).is_ok());
+    }
+
+    #[test]
+    fn freed_slabs_make_room_for_new_ones() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        alloc.set_slab_size_bits(8).unwrap();
+        let per_slab = 1 << (8 - seL4_EndpointBits);
+
+        let eps: Vec<_> = (0..(MAX_SLABS * per_slab))
+            .map(|_| alloc.slab_alloc(ObjectType::Endpoint).unwrap())
+            .collect();
+        assert_eq!(
+            alloc.slab_alloc(ObjectType::Notification),
+            Err(Error::SlabsExhausted)
+        );
+
+        for &ep in eps[..per_slab].iter() {
+            alloc.slab_free(ep).unwrap();
+        }
+        assert!(alloc.slab_alloc(ObjectType::Notification).is_ok());
+    }
+
+    #[test]
+    fn long_lived_objects_dont_pin_their_slabs() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        alloc.set_slab_size_bits(8).unwrap();
+        let per_slab = 1 << (8 - seL4_EndpointBits);
+
+        // Every so often an object is kept, the rest are freed straight away
+        let mut kept = Vec::new();
+        for i in 0..(4 * MAX_SLABS * per_slab) {
+            let ep = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+            if (i % per_slab == 0) && (kept.len() < MAX_SLABS) {
+                kept.push(ep);
+            } else {
+                alloc.slab_free(ep).unwrap();
+            }
+        }
+
+        assert_eq!(kept.len(), MAX_SLABS);
+        assert_eq!(
+            alloc.slabs.iter().filter(|s| s.item_type.is_some()).count(),
+            MAX_SLABS / per_slab
+        );
+    }
+
+    #[test]
+    fn slab_rejects_variable_sized_objects_and_unknown_caps() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        assert_eq!(
+            alloc.slab_alloc(ObjectType::Untyped),
+            Err(Error::SizeOutOfRange(0))
+        );
+        assert_eq!(alloc.slab_free(1234), Err(Error::UnknownSlabObject(1234)));
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn slab_size_is_configurable() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+        assert!(alloc.set_slab_size_bits(MAX_UNTYPED_SIZE + 1).is_err());
+        alloc.set_slab_size_bits(8).unwrap();
+
+        let first = alloc.slab_alloc(ObjectType::Endpoint).unwrap();
+        let ut = alloc.kernel.cap_parent(first).unwrap();
+        let slab_ut = alloc.kernel.cap_parent(ut).unwrap();
+        assert_eq!(alloc.kernel.cap_size_bits(slab_ut), Some(8));
+    }
+}
@@ -0,0 +1,369 @@
+/// Buddy allocator over untyped memory.
+///
+/// Every untyped we know about is a node in a binary tree, initial memory
+/// items being the roots. Allocating splits a free node in half (by
+/// retyping it into two untypeds) until one of the requested size is
+/// available. Freeing revokes the untyped and, once both halves of a split
+/// are free again, revokes the parent to coalesce them back into one.
+use super::{
+    Allocator, Error, Kernel, ObjectType, Operation, UntypedItem, UntypedNode, UntypedState,
+    MAX_UNTYPED_SIZE, MIN_UNTYPED_SIZE,
+};
+use sel4_sys::{seL4_CPtr, seL4_Word};
+
+impl UntypedNode {
+    pub(crate) const fn unused() -> Self {
+        UntypedNode {
+            item: UntypedItem {
+                cap: 0,
+                size_bits: 0,
+                paddr: 0,
+                is_device: false,
+            },
+            state: UntypedState::Unused,
+            parent: None,
+            children: [0, 0],
+        }
+    }
+
+    /// Whether this node covers 'size_bits' bytes starting at 'paddr'
+    fn contains(&self, paddr: seL4_Word, size_bits: usize) -> bool {
+        let start = self.item.paddr;
+        let end = start + ((1 as seL4_Word) << self.item.size_bits);
+
+        (paddr >= start)
+            && (((paddr - start) & (((1 as seL4_Word) << size_bits) - 1)) == 0)
+            && ((paddr + ((1 as seL4_Word) << size_bits)) <= end)
+    }
+}
+
+impl<K: Kernel> Allocator<K> {
+    /// Allocate untyped item of size 'size_bits' bits.
+    ///
+    /// If 'paddr' is given, the untyped will start at that physical address.
+    /// Device memory is only used if 'can_use_dev' is set.
+    pub fn alloc_untyped(
+        &mut self,
+        size_bits: usize,
+        paddr: Option<seL4_Word>,
+        can_use_dev: bool,
+    ) -> Result<seL4_CPtr, Error> {
+        // If it is too small or too big, not much we can do
+        if size_bits < MIN_UNTYPED_SIZE {
+            return Err(Error::SizeOutOfRange(size_bits));
+        }
+        if size_bits > MAX_UNTYPED_SIZE {
+            return Err(Error::SizeOutOfRange(size_bits));
+        }
+
+        let mut node = self.find_free_untyped(size_bits, paddr, can_use_dev)?;
+
+        // Split it down to the size we want
+        while self.untyped_nodes[node].item.size_bits > size_bits {
+            let children = self.split_untyped(node)?;
+
+            node = match paddr {
+                Some(paddr) if !self.untyped_nodes[children[0]].contains(paddr, size_bits) => {
+                    children[1]
+                }
+                _ => children[0],
+            };
+        }
+
+        self.untyped_nodes[node].state = UntypedState::Allocated;
+
+        Ok(self.untyped_nodes[node].item.cap)
+    }
+
+    /// Return an untyped item from alloc_untyped() to the allocator.
+    ///
+    /// The untyped is revoked, destroying anything retyped from it. If its
+    /// buddy is also free, the two are merged back into their parent.
+    pub fn free_untyped(&mut self, cap: seL4_CPtr) -> Result<(), Error> {
+        let mut node = self.find_allocated_untyped(cap)?;
+
+        self.revoke_untyped(node)?;
+        self.untyped_nodes[node].state = UntypedState::Free;
+
+        // Coalesce with our buddy for as long as we can
+        while let Some(parent) = self.untyped_nodes[node].parent {
+            let children = self.untyped_nodes[parent].children;
+            if (self.untyped_nodes[children[0]].state != UntypedState::Free)
+                || (self.untyped_nodes[children[1]].state != UntypedState::Free)
+            {
+                break;
+            }
+
+            // Revoking the parent deletes both halves
+            self.revoke_untyped(parent)?;
+
+            for child in children.iter() {
+                let child_cap = self.untyped_nodes[*child].item.cap;
+                self.free_cslot(child_cap);
+                self.untyped_nodes[*child] = UntypedNode::unused();
+            }
+
+            self.untyped_nodes[parent].state = UntypedState::Free;
+            node = parent;
+        }
+
+        Ok(())
+    }
+
+    /// Physical address of an untyped handed out by alloc_untyped().
+    pub fn untyped_paddr(&self, cap: seL4_CPtr) -> Result<seL4_Word, Error> {
+        let node = self.find_allocated_untyped(cap)?;
+
+        Ok(self.untyped_nodes[node].item.paddr)
+    }
+
+    /// Record a new node in the buddy tree.
+    pub(crate) fn new_untyped_node(
+        &mut self,
+        item: UntypedItem,
+        parent: Option<usize>,
+    ) -> Result<usize, Error> {
+        let node = self
+            .untyped_nodes
+            .iter()
+            .position(|n| n.state == UntypedState::Unused)
+            .ok_or(Error::UntypedNodesExhausted)?;
+
+        self.untyped_nodes[node] = UntypedNode {
+            item,
+            state: UntypedState::Free,
+            parent,
+            children: [0, 0],
+        };
+
+        Ok(node)
+    }
+
+    /// Find the smallest free node that can provide an untyped of
+    /// 'size_bits' bits, preferring regular memory over device memory.
+    fn find_free_untyped(
+        &self,
+        size_bits: usize,
+        paddr: Option<seL4_Word>,
+        can_use_dev: bool,
+    ) -> Result<usize, Error> {
+        let mut best: Option<usize> = None;
+
+        for (i, node) in self.untyped_nodes.iter().enumerate() {
+            if (node.state != UntypedState::Free) || (node.item.size_bits < size_bits) {
+                continue;
+            }
+
+            if node.item.is_device && !can_use_dev {
+                continue;
+            }
+
+            if let Some(paddr) = paddr {
+                if !node.contains(paddr, size_bits) {
+                    continue;
+                }
+            }
+
+            best = match best {
+                Some(b) => {
+                    let cur = &self.untyped_nodes[b];
+                    if (node.item.size_bits, node.item.is_device)
+                        < (cur.item.size_bits, cur.item.is_device)
+                    {
+                        Some(i)
+                    } else {
+                        Some(b)
+                    }
+                }
+                None => Some(i),
+            };
+        }
+
+        best.ok_or(Error::ResourceExhausted)
+    }
+
+    fn find_allocated_untyped(&self, cap: seL4_CPtr) -> Result<usize, Error> {
+        self.untyped_nodes
+            .iter()
+            .position(|n| (n.state == UntypedState::Allocated) && (n.item.cap == cap))
+            .ok_or(Error::UnknownUntyped(cap))
+    }
+
+    /// Retype a free node into two halves, returning the new nodes.
+    fn split_untyped(&mut self, node: usize) -> Result<[usize; 2], Error> {
+        let parent = self.untyped_nodes[node].item;
+        assert!(parent.size_bits > MIN_UNTYPED_SIZE);
+
+        // Make sure we can track both halves before touching the kernel
+        let num_unused = self
+            .untyped_nodes
+            .iter()
+            .filter(|n| n.state == UntypedState::Unused)
+            .count();
+        if num_unused < 2 {
+            return Err(Error::UntypedNodesExhausted);
+        }
+
+        let child_size_bits = parent.size_bits - 1;
+        let range =
+            self.retype_untyped_memory(parent.cap, ObjectType::Untyped, child_size_bits, 2)?;
+        assert!(range.count == 2);
+
+        let mut children = [0; 2];
+        for (i, child) in children.iter_mut().enumerate() {
+            let item = UntypedItem {
+                cap: (range.first + i) as _,
+                size_bits: child_size_bits,
+                paddr: parent.paddr + ((i as seL4_Word) << child_size_bits),
+                is_device: parent.is_device,
+            };
+            *child = self.new_untyped_node(item, Some(node))?;
+        }
+
+        self.untyped_nodes[node].state = UntypedState::Split;
+        self.untyped_nodes[node].children = children;
+
+        Ok(children)
+    }
+
+    fn revoke_untyped(&mut self, node: usize) -> Result<(), Error> {
+        let err = self.kernel.cnode_revoke(
+            self.root_cnode,
+            self.untyped_nodes[node].item.cap,
+            self.root_cnode_depth as _,
+        );
+
+        Error::check(Operation::CNodeRevoke, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sel4_sys::*;
+    use sim;
+
+    #[test]
+    fn alloc_untyped_rejects_unsupported_sizes() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+
+        assert!(alloc
+            .alloc_untyped(MIN_UNTYPED_SIZE - 1, None, false)
+            .is_err());
+        assert!(alloc
+            .alloc_untyped(MAX_UNTYPED_SIZE + 1, None, false)
+            .is_err());
+    }
+
+    #[test]
+    fn alloc_untyped_hands_out_untyped_of_at_least_the_requested_size() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false), (0x2000_0000, 16, false)]);
+
+        let ut = alloc.alloc_untyped(14, None, false).unwrap();
+        assert_eq!(
+            alloc.kernel.cap_type(ut),
+            Some(api_object_seL4_UntypedObject)
+        );
+        assert!(alloc.kernel.cap_size_bits(ut).unwrap() >= 14);
+    }
+
+    #[test]
+    fn alloc_untyped_reuses_the_other_half_of_a_split() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        let first = alloc.alloc_untyped(11, None, false).unwrap();
+        let second = alloc.alloc_untyped(11, None, false).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(alloc.kernel.cap_size_bits(first), Some(11));
+        assert_eq!(alloc.kernel.cap_size_bits(second), Some(11));
+
+        let mut paddrs = [
+            alloc.kernel.cap_paddr(first).unwrap(),
+            alloc.kernel.cap_paddr(second).unwrap(),
+        ];
+        paddrs.sort();
+        assert_eq!(paddrs, [0x1000_0000, 0x1000_0800]);
+
+        // Both halves are in use now
+        assert!(alloc.alloc_untyped(11, None, false).is_err());
+    }
+
+    #[test]
+    fn alloc_untyped_splits_all_the_way_down() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        // 2^12 bytes hold 2^(12 - 4) of the smallest untypeds
+        for _ in 0..(1 << (12 - MIN_UNTYPED_SIZE)) {
+            let ut = alloc.alloc_untyped(MIN_UNTYPED_SIZE, None, false).unwrap();
+            assert_eq!(alloc.kernel.cap_size_bits(ut), Some(MIN_UNTYPED_SIZE));
+        }
+        assert!(alloc.alloc_untyped(MIN_UNTYPED_SIZE, None, false).is_err());
+    }
+
+    #[test]
+    fn alloc_untyped_only_uses_device_memory_when_allowed() {
+        let mut alloc = sim::allocator(&[(0x0200_0000, 12, true)]);
+
+        assert!(alloc.alloc_untyped(12, None, false).is_err());
+        let ut = alloc.alloc_untyped(12, Some(0x0200_0000), true).unwrap();
+        assert_eq!(alloc.kernel.cap_paddr(ut), Some(0x0200_0000));
+    }
+
+    #[test]
+    fn alloc_untyped_splits_towards_paddr() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        let ut = alloc.alloc_untyped(12, Some(0x1000_3000), false).unwrap();
+        assert_eq!(alloc.kernel.cap_paddr(ut), Some(0x1000_3000));
+        assert_eq!(alloc.kernel.cap_size_bits(ut), Some(12));
+        assert_eq!(alloc.untyped_paddr(ut), Ok(0x1000_3000));
+
+        // Misaligned or outside of what we have
+        assert!(alloc.alloc_untyped(12, Some(0x1000_3800), false).is_err());
+        assert!(alloc.alloc_untyped(12, Some(0x2000_0000), false).is_err());
+    }
+
+    #[test]
+    fn free_untyped_coalesces_buddies() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let root = sim::SIM_FIRST_FREE_SLOT - 1;
+
+        let first = alloc.alloc_untyped(10, None, false).unwrap();
+        let second = alloc.alloc_untyped(10, None, false).unwrap();
+        assert!(alloc.alloc_untyped(12, None, false).is_err());
+
+        alloc.free_untyped(first).unwrap();
+        assert!(alloc.alloc_untyped(12, None, false).is_err());
+
+        alloc.free_untyped(second).unwrap();
+        assert_eq!(alloc.alloc_untyped(12, None, false), Ok(root));
+
+        // Only the root cap is left in the kernel
+        assert_eq!(alloc.kernel.cap_type(first), None);
+        assert_eq!(alloc.kernel.cap_type(second), None);
+    }
+
+    #[test]
+    fn free_untyped_destroys_objects_and_resets_memory() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        let ut = alloc.alloc_untyped(12, None, false).unwrap();
+        let range = alloc
+            .retype_untyped_memory(ut, ObjectType::Tcb, 0, 8)
+            .unwrap();
+        alloc.free_untyped(ut).unwrap();
+        assert_eq!(alloc.kernel.cap_type(range.first as _), None);
+
+        let ut = alloc.alloc_untyped(12, None, false).unwrap();
+        assert!(alloc
+            .retype_untyped_memory(ut, ObjectType::Tcb, 0, 8)
+            .is_ok());
+    }
+
+    #[test]
+    fn free_untyped_rejects_unknown_caps() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        assert!(alloc.free_untyped(sim::SIM_FIRST_FREE_SLOT - 1).is_err());
+    }
+}
@@ -1,68 +1,135 @@
-/// TODO - need a proper VKA abstration and implementation
-use super::{Allocator, Error};
+/// Virtual kernel allocator interface.
+///
+/// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/vka.h
+use super::{Allocator, Error, Kernel, ObjectType, Operation};
 use cspacepath::CSpacePath;
 use sel4_sys::*;
 
-impl Allocator {
-    /// Get the size (in bits) of the untyped memory required to create an
-    /// object of the given size.
+/// Cslot and untyped memory allocation, as provided by libsel4vka's vka_t.
+///
+/// The object helpers (VkaObjectExt) are built on top of this, so they work
+/// with any implementor.
+pub trait Vka {
+    /// Allocate an empty cslot.
+    fn vka_cspace_alloc(&mut self) -> Result<seL4_CPtr, Error>;
+
+    /// Free a cslot from vka_cspace_alloc(), it should be empty.
+    fn vka_cspace_free(&mut self, slot: seL4_CPtr);
+
+    /// The path to 'slot', as needed by CNode invocations.
+    fn vka_cspace_make_path(&self, slot: seL4_CPtr) -> CSpacePath;
+
+    /// Retype untyped memory into an object of 'item_type' at 'dest'.
     ///
-    /// TODO - see vka/object.h, not handling all cases yet (feature gating for
-    /// RT/etc)
-    /// TODO - move this once vka_object/vka works
-    /// https://github.com/seL4/seL4_libs/blob/c7e4a85edc3048979fbc962ace2dbe657e8d0b3c/libsel4vka/arch_include/arm/vka/arch/kobject_t.h#L50
-    pub fn vka_get_object_size(&self, obj_type: seL4_Word, obj_size_bits: usize) -> usize {
-        #[allow(non_upper_case_globals)]
-        match obj_type {
-            api_object_seL4_UntypedObject => obj_size_bits as _,
-            api_object_seL4_TCBObject => seL4_TCBBits as _,
-            api_object_seL4_EndpointObject => seL4_EndpointBits as _,
-            api_object_seL4_NotificationObject => seL4_NotificationBits as _,
-            api_object_seL4_CapTableObject => (seL4_SlotBits as usize + obj_size_bits),
-            //seL4_KernelImageObject => seL4_KernelImageBits,
-            _ => self.vka_arch_get_object_size(obj_type),
-        }
-    }
+    /// Returns a cookie identifying the memory for vka_utspace_free().
+    fn vka_utspace_alloc(
+        &mut self,
+        dest: &CSpacePath,
+        item_type: ObjectType,
+        size_bits: usize,
+    ) -> Result<seL4_Word, Error>;
+
+    /// As vka_utspace_alloc(), device memory may be used if 'can_use_dev'
+    /// is set.
+    fn vka_utspace_alloc_maybe_device(
+        &mut self,
+        dest: &CSpacePath,
+        item_type: ObjectType,
+        size_bits: usize,
+        can_use_dev: bool,
+    ) -> Result<seL4_Word, Error>;
 
+    /// As vka_utspace_alloc(), with the object at physical address 'paddr'.
+    fn vka_utspace_alloc_at(
+        &mut self,
+        dest: &CSpacePath,
+        item_type: ObjectType,
+        size_bits: usize,
+        paddr: seL4_Word,
+        can_use_dev: bool,
+    ) -> Result<seL4_Word, Error>;
+
+    /// Return memory from vka_utspace_alloc() or vka_utspace_alloc_at(),
+    /// anything retyped from it is destroyed.
+    fn vka_utspace_free(
+        &mut self,
+        item_type: ObjectType,
+        size_bits: usize,
+        ut: seL4_Word,
+    ) -> Result<(), Error>;
+
+    /// Physical address of the memory identified by 'ut'.
+    fn vka_utspace_paddr(
+        &self,
+        ut: seL4_Word,
+        item_type: ObjectType,
+        size_bits: usize,
+    ) -> Result<seL4_Word, Error>;
+
+    /// Delete the cap at 'path' (seL4_CNode_Delete).
+    ///
+    /// Frames mapped by the vspace functions have to be unmapped first,
+    /// Error::StillMapped otherwise.
+    fn vka_cnode_delete(&mut self, path: &CSpacePath) -> Result<(), Error>;
+}
+
+impl<K: Kernel> Allocator<K> {
     /// Get the size (in bits) of the untyped memory required to create an
     /// object of the given size.
-    /// TODO - feature gate for arm, SMMU
-    pub fn vka_arch_get_object_size(&self, obj_type: seL4_Word) -> usize {
-        #[allow(non_upper_case_globals)]
-        match obj_type {
-            _object_seL4_ARM_SmallPageObject => seL4_PageBits as _,
-            _object_seL4_ARM_LargePageObject => seL4_LargePageBits as _,
-            _object_seL4_ARM_PageTableObject => seL4_PageTableBits as _,
-            _object_seL4_ARM_PageDirectoryObject => seL4_PageDirBits as _,
-            _ => self.vka_arm_mode_get_object_size(obj_type),
-        }
+    ///
+    /// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/object.h
+    pub fn vka_get_object_size(
+        &self,
+        obj_type: ObjectType,
+        obj_size_bits: usize,
+    ) -> Result<usize, Error> {
+        obj_type.size_bits(obj_size_bits)
     }
 
-    /// Get the size (in bits) of the untyped memory required to create an
-    /// object of the given size.
-    /// TODO - feature gate for aarch32/aarch64
-    pub fn vka_arm_mode_get_object_size(&self, obj_type: seL4_Word) -> usize {
-        #[allow(non_upper_case_globals)]
-        match obj_type {
-            _object_seL4_ARM_SectionObject => seL4_SectionBits as _,
-            _object_seL4_ARM_SuperSectionObject => seL4_SuperSectionBits as _,
-            seL4_ARM_VCPUObject => seL4_ARM_VCPUBits as _,
-            _ => panic!("Unknown object type"),
+    fn utspace_alloc(
+        &mut self,
+        dest: &CSpacePath,
+        item_type: ObjectType,
+        size_bits: usize,
+        paddr: Option<seL4_Word>,
+        can_use_dev: bool,
+    ) -> Result<seL4_Word, Error> {
+        let ut_size_bits = self.vka_get_object_size(item_type, size_bits)?;
+
+        // allocate untyped memory the size we want
+        let untyped_memory = self.alloc_untyped(ut_size_bits, paddr, can_use_dev)?;
+
+        let err = self.kernel.untyped_retype(
+            untyped_memory,
+            item_type.to_sel4(),
+            size_bits as _,
+            dest,
+            1,
+        );
+
+        if let Err(e) = Error::check(Operation::Retype, err) {
+            // Don't hang on to the memory if we couldn't use it
+            let _ = self.free_untyped(untyped_memory);
+            return Err(e);
         }
+
+        Ok(untyped_memory as _)
     }
+}
 
-    pub fn vka_cspace_alloc(&mut self) -> Result<seL4_CPtr, Error> {
+impl<K: Kernel> Vka for Allocator<K> {
+    fn vka_cspace_alloc(&mut self) -> Result<seL4_CPtr, Error> {
         self.alloc_cslot()
     }
 
-    pub fn vka_cspace_free(&mut self, slot: seL4_CPtr) {
+    fn vka_cspace_free(&mut self, slot: seL4_CPtr) {
         self.free_cslot(slot)
     }
 
-    pub fn vka_cspace_make_path(&self, slot: seL4_CPtr) -> CSpacePath {
+    fn vka_cspace_make_path(&self, slot: seL4_CPtr) -> CSpacePath {
         CSpacePath {
             cap_ptr: slot,
-            cap_depth: 32,
+            cap_depth: self.root_cnode_depth,
             root: self.root_cnode,
             dest: self.root_cnode,
             dest_depth: self.root_cnode_depth,
@@ -71,56 +138,62 @@ impl Allocator {
         }
     }
 
-    pub fn vka_utspace_alloc(
+    fn vka_utspace_alloc(
         &mut self,
         dest: &CSpacePath,
-        item_type: seL4_Word,
+        item_type: ObjectType,
         size_bits: usize,
-    ) -> Result<seL4_CPtr, Error> {
+    ) -> Result<seL4_Word, Error> {
         self.utspace_alloc(dest, item_type, size_bits, None, false)
     }
 
-    pub fn vka_utspace_alloc_at(
+    fn vka_utspace_alloc_maybe_device(
         &mut self,
         dest: &CSpacePath,
-        item_type: seL4_Word,
+        item_type: ObjectType,
         size_bits: usize,
-        paddr: seL4_Word,
         can_use_dev: bool,
-    ) -> Result<seL4_CPtr, Error> {
-        self.utspace_alloc(dest, item_type, size_bits, Some(paddr), can_use_dev)
+    ) -> Result<seL4_Word, Error> {
+        self.utspace_alloc(dest, item_type, size_bits, None, can_use_dev)
     }
 
-    fn utspace_alloc(
+    fn vka_utspace_alloc_at(
         &mut self,
         dest: &CSpacePath,
-        item_type: seL4_Word,
+        item_type: ObjectType,
         size_bits: usize,
-        paddr: Option<seL4_Word>,
+        paddr: seL4_Word,
         can_use_dev: bool,
-    ) -> Result<seL4_CPtr, Error> {
-        let ut_size_bits = self.vka_get_object_size(item_type, size_bits);
+    ) -> Result<seL4_Word, Error> {
+        self.utspace_alloc(dest, item_type, size_bits, Some(paddr), can_use_dev)
+    }
 
-        // allocate untyped memory the size we want
-        let untyped_memory = self.alloc_untyped(ut_size_bits, paddr, can_use_dev)?;
+    fn vka_utspace_free(
+        &mut self,
+        _item_type: ObjectType,
+        _size_bits: usize,
+        ut: seL4_Word,
+    ) -> Result<(), Error> {
+        self.free_untyped(ut as _)
+    }
 
-        let err = unsafe {
-            seL4_Untyped_Retype(
-                untyped_memory,
-                item_type,
-                size_bits as _,
-                seL4_CapInitThreadCNode,
-                self.root_cnode,
-                self.root_cnode_depth,
-                dest.cap_ptr,
-                1,
-            )
-        };
-
-        if err == 0 {
-            Ok(untyped_memory)
-        } else {
-            Err(Error::ResourceExhausted)
+    fn vka_utspace_paddr(
+        &self,
+        ut: seL4_Word,
+        _item_type: ObjectType,
+        _size_bits: usize,
+    ) -> Result<seL4_Word, Error> {
+        self.untyped_paddr(ut as _)
+    }
+
+    fn vka_cnode_delete(&mut self, path: &CSpacePath) -> Result<(), Error> {
+        if let Some(page) = self.vspace_mapped_page(path.cap_ptr) {
+            return Err(Error::StillMapped(page.vaddr()));
         }
+
+        let err = self
+            .kernel
+            .cnode_delete(path.root, path.cap_ptr, path.cap_depth as _);
+        Error::check(Operation::CNodeDelete, err)
     }
 }
@@ -1,5 +1,5 @@
 /// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/object.h
-use super::{Allocator, Error};
+use super::{Allocator, CapRange, Error, Kernel, ObjectType, Vka};
 use sel4_sys::*;
 
 /// A wrapper to hold all the allocation information for an 'object'.
@@ -7,106 +7,465 @@ use sel4_sys::*;
 /// An object here is just combination of cptr and untyped allocation
 /// The type and size of the allocation is also stored to make free
 /// more convenient.
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct VkaObject {
     pub cptr: seL4_CPtr,
     pub ut: seL4_Word,
-    pub item_type: seL4_Word,
+    pub item_type: ObjectType,
     pub size_bits: seL4_Word,
 }
 
 impl VkaObject {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         VkaObject {
             cptr: 0,
             ut: 0,
-            item_type: 0,
+            item_type: ObjectType::Untyped,
             size_bits: 0,
         }
     }
 }
 
-impl Allocator {
-    pub fn vka_alloc_untyped(&mut self, size_bits: usize) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(api_object_seL4_UntypedObject, size_bits)
+impl Default for VkaObject {
+    fn default() -> Self {
+        VkaObject::new()
     }
+}
+
+/// A batch of objects of the same type, retyped together from a single
+/// untyped into a contiguous run of cslots.
+#[derive(Clone, Debug)]
+pub struct VkaObjects {
+    pub caps: CapRange,
+    pub ut: seL4_Word,
+    pub item_type: ObjectType,
+    pub size_bits: seL4_Word,
+}
 
-    pub fn vka_alloc_tcb(&mut self) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(api_object_seL4_TCBObject, seL4_TCBBits as _)
+/// The object helpers from libsel4vka's object.h, for any Vka implementor.
+pub trait VkaObjectExt: Vka {
+    fn vka_alloc_untyped(&mut self, size_bits: usize) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::Untyped, size_bits)
     }
 
-    pub fn vka_alloc_endpoint(&mut self) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(api_object_seL4_EndpointObject, seL4_EndpointBits as _)
+    fn vka_alloc_tcb(&mut self) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::Tcb, seL4_TCBBits as _)
     }
 
-    pub fn vka_alloc_notification(&mut self) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(
-            api_object_seL4_NotificationObject,
-            seL4_NotificationBits as _,
+    fn vka_alloc_endpoint(&mut self) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::Endpoint, seL4_EndpointBits as _)
+    }
+
+    fn vka_alloc_notification(&mut self) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::Notification, seL4_NotificationBits as _)
+    }
+
+    #[cfg(feature = "mcs")]
+    fn vka_alloc_sched_context(&mut self, size_bits: usize) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::SchedContext, size_bits)
+    }
+
+    #[cfg(feature = "mcs")]
+    fn vka_alloc_reply(&mut self) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::Reply, seL4_ReplyBits as _)
+    }
+
+    fn vka_alloc_frame(&mut self, size_bits: usize) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::frame(size_bits)?, size_bits)
+    }
+
+    /// A frame that may be device memory if 'can_use_dev' is set.
+    fn vka_alloc_frame_maybe_device(
+        &mut self,
+        size_bits: usize,
+        can_use_dev: bool,
+    ) -> Result<VkaObject, Error> {
+        alloc_object_at_maybe_dev(
+            self,
+            ObjectType::frame(size_bits)?,
+            size_bits,
+            None,
+            can_use_dev,
         )
     }
 
-    // TODO - need to do kobject_get_type()
-    pub fn vka_alloc_frame(&mut self, size_bits: usize) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(_object_seL4_ARM_SmallPageObject, size_bits)
+    /// A frame at 'paddr', which may be device memory.
+    fn vka_alloc_frame_at(
+        &mut self,
+        size_bits: usize,
+        paddr: seL4_Word,
+    ) -> Result<VkaObject, Error> {
+        self.vka_alloc_object_at(ObjectType::frame(size_bits)?, size_bits, paddr)
     }
 
-    pub fn vka_alloc_frame_at(
+    /// A frame at 'paddr', which may be device memory only if 'can_use_dev'
+    /// is set.
+    fn vka_alloc_frame_at_maybe_device(
         &mut self,
         size_bits: usize,
         paddr: seL4_Word,
+        can_use_dev: bool,
     ) -> Result<VkaObject, Error> {
-        self.vka_alloc_object_at(_object_seL4_ARM_SmallPageObject, size_bits, paddr)
+        self.vka_alloc_object_at_maybe_device(
+            ObjectType::frame(size_bits)?,
+            size_bits,
+            paddr,
+            can_use_dev,
+        )
     }
 
-    pub fn vka_alloc_page_table(&mut self) -> Result<VkaObject, Error> {
-        self.vka_alloc_object(_object_seL4_ARM_PageTableObject, seL4_PageTableBits as _)
+    fn vka_alloc_page_table(&mut self) -> Result<VkaObject, Error> {
+        self.vka_alloc_object(ObjectType::PageTable, seL4_PageTableBits as _)
     }
 
-    pub fn vka_alloc_object(
+    fn vka_alloc_object(
         &mut self,
-        obj_type: seL4_Word,
+        obj_type: ObjectType,
         size_bits: usize,
     ) -> Result<VkaObject, Error> {
-        self.alloc_object_at_maybe_dev(obj_type, size_bits, None, false)
+        alloc_object_at_maybe_dev(self, obj_type, size_bits, None, false)
     }
 
-    pub fn vka_alloc_object_at(
+    fn vka_alloc_object_at(
         &mut self,
-        obj_type: seL4_Word,
+        obj_type: ObjectType,
         size_bits: usize,
         paddr: seL4_Word,
     ) -> Result<VkaObject, Error> {
-        self.alloc_object_at_maybe_dev(obj_type, size_bits, Some(paddr), true)
+        self.vka_alloc_object_at_maybe_device(obj_type, size_bits, paddr, true)
     }
 
-    /// Generic object allocator.
-    /// TODO - use latest from seL4, this is from SMACCM repo
-    /// https://github.com/smaccm/seL4_libs/blob/master/libsel4vka/include/vka/object.h#L38
-    /// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/object.h#L75
-    fn alloc_object_at_maybe_dev(
+    fn vka_alloc_object_at_maybe_device(
         &mut self,
-        obj_type: seL4_Word,
+        obj_type: ObjectType,
         size_bits: usize,
-        paddr: Option<seL4_Word>,
+        paddr: seL4_Word,
         can_use_dev: bool,
     ) -> Result<VkaObject, Error> {
-        let mut result: VkaObject = VkaObject::new();
+        alloc_object_at_maybe_dev(self, obj_type, size_bits, Some(paddr), can_use_dev)
+    }
+
+    /// Free an object allocated with one of the vka_alloc_* functions.
+    ///
+    /// Deletes the object cap, returns the backing untyped memory to the
+    /// allocator and releases the cslot.
+    ///
+    /// Frames mapped by the vspace functions have to be unmapped first, see
+    /// vspace_unmap_pages().
+    fn vka_free_object(&mut self, object: &VkaObject) -> Result<(), Error> {
+        let path = self.vka_cspace_make_path(object.cptr);
+        self.vka_cnode_delete(&path)?;
+
+        self.vka_cspace_free(object.cptr);
+
+        self.vka_utspace_free(object.item_type, object.size_bits as _, object.ut)
+    }
+
+    /// Physical address of an object from one of the vka_alloc_* functions.
+    fn vka_object_paddr(&self, object: &VkaObject) -> Result<seL4_Word, Error> {
+        self.vka_utspace_paddr(object.ut, object.item_type, object.size_bits as _)
+    }
+}
+
+impl<V: Vka + ?Sized> VkaObjectExt for V {}
+
+impl<K: Kernel> Allocator<K> {
+    pub fn vka_alloc_endpoints(&mut self, num_objects: usize) -> Result<VkaObjects, Error> {
+        self.vka_alloc_objects(ObjectType::Endpoint, seL4_EndpointBits as _, num_objects)
+    }
 
-        result.cptr = self.vka_cspace_alloc()?;
+    pub fn vka_alloc_notifications(&mut self, num_objects: usize) -> Result<VkaObjects, Error> {
+        self.vka_alloc_objects(
+            ObjectType::Notification,
+            seL4_NotificationBits as _,
+            num_objects,
+        )
+    }
+
+    /// Allocate 'num_objects' objects with a single retype, see
+    /// alloc_kobjects().
+    pub fn vka_alloc_objects(
+        &mut self,
+        obj_type: ObjectType,
+        size_bits: usize,
+        num_objects: usize,
+    ) -> Result<VkaObjects, Error> {
+        let (ut, caps) = self.alloc_kobjects(obj_type, size_bits, num_objects)?;
 
-        let path = self.vka_cspace_make_path(result.cptr);
+        Ok(VkaObjects {
+            caps,
+            ut: ut as _,
+            item_type: obj_type,
+            size_bits: size_bits as _,
+        })
+    }
+
+    /// Free a batch from vka_alloc_objects().
+    ///
+    /// Returning the untyped destroys every object in the batch, then the
+    /// cslots are released.
+    pub fn vka_free_objects(&mut self, objects: &VkaObjects) -> Result<(), Error> {
+        self.vka_utspace_free(objects.item_type, objects.size_bits as _, objects.ut)?;
+
+        self.free_cslot_range(&objects.caps);
+
+        Ok(())
+    }
+}
 
-        if let Some(paddr) = paddr {
-            result.ut =
-                self.vka_utspace_alloc_at(&path, obj_type, size_bits, paddr, can_use_dev)?;
-        } else {
-            result.ut = self.vka_utspace_alloc(&path, obj_type, size_bits)?;
+/// Generic object allocator.
+/// TODO - use latest from seL4, this is from SMACCM repo
+/// https://github.com/smaccm/seL4_libs/blob/master/libsel4vka/include/vka/object.h#L38
+/// https://github.com/seL4/seL4_libs/blob/master/libsel4vka/include/vka/object.h#L75
+fn alloc_object_at_maybe_dev<V: Vka + ?Sized>(
+    vka: &mut V,
+    obj_type: ObjectType,
+    size_bits: usize,
+    paddr: Option<seL4_Word>,
+    can_use_dev: bool,
+) -> Result<VkaObject, Error> {
+    let mut result: VkaObject = VkaObject::new();
+
+    result.cptr = vka.vka_cspace_alloc()?;
+
+    let path = vka.vka_cspace_make_path(result.cptr);
+
+    let ut = if let Some(paddr) = paddr {
+        vka.vka_utspace_alloc_at(&path, obj_type, size_bits, paddr, can_use_dev)
+    } else {
+        vka.vka_utspace_alloc_maybe_device(&path, obj_type, size_bits, can_use_dev)
+    };
+    result.ut = match ut {
+        Ok(ut) => ut,
+        Err(e) => {
+            vka.vka_cspace_free(result.cptr);
+            return Err(e);
+        }
+    };
+
+    result.item_type = obj_type;
+    result.size_bits = size_bits as _;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arch::DEFAULT_VM_ATTRIBUTES;
+    use cspacepath::CSpacePath;
+    use sim::{self, SimKernel};
+
+    /// Another allocator, that passes everything through to ours while
+    /// counting the cslots it has out.
+    struct CountingVka {
+        alloc: Allocator<SimKernel>,
+        cslots: usize,
+    }
+
+    impl Vka for CountingVka {
+        fn vka_cspace_alloc(&mut self) -> Result<seL4_CPtr, Error> {
+            self.cslots += 1;
+            self.alloc.vka_cspace_alloc()
+        }
+
+        fn vka_cspace_free(&mut self, slot: seL4_CPtr) {
+            self.cslots -= 1;
+            self.alloc.vka_cspace_free(slot)
         }
 
-        result.item_type = obj_type;
-        result.size_bits = size_bits as _;
+        fn vka_cspace_make_path(&self, slot: seL4_CPtr) -> CSpacePath {
+            self.alloc.vka_cspace_make_path(slot)
+        }
+
+        fn vka_utspace_alloc(
+            &mut self,
+            dest: &CSpacePath,
+            item_type: ObjectType,
+            size_bits: usize,
+        ) -> Result<seL4_Word, Error> {
+            self.alloc.vka_utspace_alloc(dest, item_type, size_bits)
+        }
+
+        fn vka_utspace_alloc_maybe_device(
+            &mut self,
+            dest: &CSpacePath,
+            item_type: ObjectType,
+            size_bits: usize,
+            can_use_dev: bool,
+        ) -> Result<seL4_Word, Error> {
+            self.alloc
+                .vka_utspace_alloc_maybe_device(dest, item_type, size_bits, can_use_dev)
+        }
+
+        fn vka_utspace_alloc_at(
+            &mut self,
+            dest: &CSpacePath,
+            item_type: ObjectType,
+            size_bits: usize,
+            paddr: seL4_Word,
+            can_use_dev: bool,
+        ) -> Result<seL4_Word, Error> {
+            self.alloc
+                .vka_utspace_alloc_at(dest, item_type, size_bits, paddr, can_use_dev)
+        }
+
+        fn vka_utspace_free(
+            &mut self,
+            item_type: ObjectType,
+            size_bits: usize,
+            ut: seL4_Word,
+        ) -> Result<(), Error> {
+            self.alloc.vka_utspace_free(item_type, size_bits, ut)
+        }
+
+        fn vka_utspace_paddr(
+            &self,
+            ut: seL4_Word,
+            item_type: ObjectType,
+            size_bits: usize,
+        ) -> Result<seL4_Word, Error> {
+            self.alloc.vka_utspace_paddr(ut, item_type, size_bits)
+        }
+
+        fn vka_cnode_delete(&mut self, path: &CSpacePath) -> Result<(), Error> {
+            self.alloc.vka_cnode_delete(path)
+        }
+    }
+
+    #[test]
+    fn object_helpers_work_with_any_vka() {
+        let mut vka = CountingVka {
+            alloc: sim::allocator(&[(0x1000_0000, 12, false)]),
+            cslots: 0,
+        };
+
+        let frame = vka
+            .vka_alloc_frame_at(seL4_PageBits as _, 0x1000_0000)
+            .unwrap();
+        assert_eq!(vka.cslots, 1);
+        assert_eq!(vka.vka_object_paddr(&frame), Ok(0x1000_0000));
+        assert_eq!(
+            vka.alloc.kernel.cap_type(frame.cptr),
+            Some(ObjectType::SmallPage.to_sel4())
+        );
+
+        vka.vka_free_object(&frame).unwrap();
+        assert_eq!(vka.cslots, 0);
+        assert_eq!(vka.alloc.kernel.cap_type(frame.cptr), None);
+    }
+
+    #[test]
+    fn free_object_returns_memory_and_cslot() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        let ep = alloc.vka_alloc_endpoint().unwrap();
+        let tcb = alloc.vka_alloc_tcb().unwrap();
+        assert_eq!(
+            alloc.kernel.cap_type(ep.cptr),
+            Some(api_object_seL4_EndpointObject)
+        );
+
+        alloc.vka_free_object(&ep).unwrap();
+        alloc.vka_free_object(&tcb).unwrap();
+        assert_eq!(alloc.kernel.cap_type(ep.cptr), None);
+        assert_eq!(alloc.kernel.cap_type(tcb.cptr), None);
+
+        // Everything coalesced back into the initial item
+        let ut = alloc.alloc_untyped(12, None, false).unwrap();
+        assert_eq!(ut, sim::SIM_FIRST_FREE_SLOT - 1);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn objects_are_freed_at_the_cnode_depth() {
+        let mut alloc = sim::allocator_with_cnode_depth(&[(0x1000_0000, 12, false)], 20);
+        assert_eq!(alloc.vka_cspace_make_path(100).cap_depth, 20);
+
+        let ep = alloc.vka_alloc_endpoint().unwrap();
+        alloc.vka_free_object(&ep).unwrap();
+        assert_eq!(alloc.kernel.cap_type(ep.cptr), None);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn mapped_frames_cant_be_freed() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        let frame = *alloc.vspace_get_page(vaddr).unwrap().frame();
+
+        // The shadow table would be left with a stale cap
+        assert_eq!(
+            alloc.vka_free_object(&frame),
+            Err(Error::StillMapped(vaddr))
+        );
+        assert!(alloc.kernel.translate(vaddr).is_some());
+        assert_eq!(
+            alloc.kernel.cap_type(frame.cptr),
+            Some(frame.item_type.to_sel4())
+        );
+        assert_eq!(alloc.vspace_get_cap(vaddr), Some(frame.cptr));
+
+        alloc
+            .vspace_unmap_pages(vaddr, 1, seL4_PageBits as _, false)
+            .unwrap();
+        alloc.vka_free_object(&frame).unwrap();
+        assert_eq!(alloc.kernel.cap_type(frame.cptr), None);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn free_objects_destroys_the_batch() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+
+        let eps = alloc.vka_alloc_endpoints(10).unwrap();
+        assert_eq!(eps.caps.count(), 10);
+        assert_eq!(
+            alloc.kernel.cap_type(eps.caps.first()),
+            Some(api_object_seL4_EndpointObject)
+        );
+
+        alloc.vka_free_objects(&eps).unwrap();
+        for i in 0..eps.caps.count() {
+            assert_eq!(alloc.kernel.cap_type(eps.caps.get(i).unwrap()), None);
+        }
+        assert_eq!(alloc.num_slots_used, 0);
+        assert!(alloc.alloc_untyped(12, None, false).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "mcs")]
+    fn mcs_objects_are_sized() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 12, false)]);
+        let sc_bits = seL4_MinSchedContextBits as usize;
+
+        let sc = alloc.vka_alloc_sched_context(sc_bits).unwrap();
+        let reply = alloc.vka_alloc_reply().unwrap();
+        assert_eq!(
+            alloc.kernel.cap_type(sc.cptr),
+            Some(api_object_seL4_SchedContextObject)
+        );
+        assert_eq!(alloc.kernel.cap_size_bits(sc.cptr), Some(sc_bits));
+        assert_eq!(
+            alloc.kernel.cap_type(reply.cptr),
+            Some(api_object_seL4_ReplyObject)
+        );
+        assert_eq!(
+            alloc.kernel.cap_size_bits(reply.cptr),
+            Some(seL4_ReplyBits as usize)
+        );
 
-        Ok(result)
+        assert_eq!(
+            alloc.vka_alloc_sched_context(sc_bits - 1).unwrap_err(),
+            Error::SizeOutOfRange(sc_bits - 1)
+        );
     }
 }
@@ -1,37 +1,38 @@
 // NOTE: this is not a proper vspace impl, just a testing area for now
-// TODO
-// - device support
-// - derive clone for CapRights
-// - get_cap(vaddr) fn to replace awkward Option<&mut seL4_CPtr> usage
 
 // https://github.com/seL4/seL4_libs/blob/master/libsel4vspace/include/vspace/vspace.h
 // https://github.com/seL4/seL4_libs/blob/master/libsel4utils/src/vspace/vspace.c
 // https://github.com/seL4/seL4_libs/blob/master/libsel4utils/src/vspace/bootstrap.c
 // https://github.com/seL4/seL4_libs/blob/master/libsel4vspace/src/sel4_arch/aarch32/mapping.c
+// https://github.com/seL4/seL4_libs/blob/master/libsel4vspace/src/sel4_arch/aarch64/mapping.c
+// https://github.com/seL4/seL4_libs/blob/master/libsel4vspace/src/sel4_arch/x86_64/mapping.c
+// https://github.com/seL4/seL4_libs/blob/master/libsel4vspace/src/arch/riscv/mapping.c
 
 // https://github.com/seL4/seL4_libs/blob/master/libsel4platsupport/src/io.c#L206
 
-use super::{Allocator, Error, VSPACE_START};
+use super::{
+    Allocator, Error, Kernel, Operation, PagingStructure, ReservationEntry, VRange, VkaObject,
+    VkaObjectExt, VmAttributes, MAX_PAGING_STRUCTURES, MAX_VSPACE_HOLES, VSPACE_START,
+};
+use arch::{DEFAULT_VM_ATTRIBUTES, FRAME_SIZES, PAGING_LEVELS};
+use core::iter;
 use sel4_sys::*;
 
-impl Allocator {
-    pub fn bootstrap_vspace(&mut self, pd_cap: seL4_CPtr) -> Result<(), Error> {
-        // set our vspace root page directory
-        self.page_directory = pd_cap;
+impl<K: Kernel> Allocator<K> {
+    pub fn bootstrap_vspace(&mut self, vspace_root: seL4_CPtr) -> Result<(), Error> {
+        // set our vspace root paging structure
+        self.vspace_root = vspace_root;
         self.last_allocated = VSPACE_START;
+        self.num_vspace_holes = 0;
         Ok(())
     }
 
-    pub fn vspace_new_ipc_buffer(
-        &mut self,
-        cap: Option<&mut seL4_CPtr>,
-    ) -> Result<seL4_Word, Error> {
+    pub fn vspace_new_ipc_buffer(&mut self) -> Result<seL4_Word, Error> {
         self.vspace_new_pages(
             1,
             seL4_PageBits as _,
             unsafe { seL4_CapRights_new(1, 1, 1) },
-            seL4_ARM_VMAttributes_seL4_ARM_Default_VMAttributes,
-            cap,
+            DEFAULT_VM_ATTRIBUTES,
         )
     }
 
@@ -39,128 +40,1781 @@ impl Allocator {
         &mut self,
         num_pages: usize,
         size_bits: usize,
-        _rights: seL4_CapRights,
-        cache_attributes: seL4_ARM_VMAttributes,
-        cap: Option<&mut seL4_CPtr>,
+        rights: seL4_CapRights,
+        cache_attributes: VmAttributes,
     ) -> Result<seL4_Word, Error> {
-        self.vspace_new_pages_at(
-            None,
-            num_pages,
-            size_bits,
-            //_rights,
-            unsafe { seL4_CapRights_new(1, 1, 1) },
-            cache_attributes,
-            false,
-            cap,
-        )
+        self.vspace_new_pages_at(None, num_pages, size_bits, rights, cache_attributes, false)
     }
 
-    // this doesn't work for multiple pages yet
+    /// Map 'num_pages' new pages of 2^'size_bits', backed by the physically
+    /// contiguous memory at 'paddr' if given.
+    ///
+    /// The frames may come from device memory if 'can_use_dev' is set.
+    ///
+    /// Every page gets a frame of its own, even where larger frames would
+    /// fit. Those are opt-in through vspace_new_large_pages_at(), as they
+    /// stop the pages being unmapped one at a time and change which cap
+    /// vspace_get_cap() gives for them.
     pub fn vspace_new_pages_at(
         &mut self,
         paddr: Option<seL4_Word>,
         num_pages: usize,
         size_bits: usize,
-        _rights: seL4_CapRights,
-        cache_attributes: seL4_ARM_VMAttributes,
-        _can_use_dev: bool,
-        cap: Option<&mut seL4_CPtr>,
-    ) -> Result<(seL4_Word), Error> {
-        let vaddr = self.last_allocated;
-        let mut page_vaddr = vaddr;
-        let mut first_cap: seL4_CPtr = 0;
-
-        for page in 0..num_pages {
-            let frame_obj = if let Some(paddr) = paddr {
-                if page == 0 {
-                    self.vka_alloc_frame_at(size_bits, paddr)?
-                } else {
-                    self.vka_alloc_frame(size_bits)?
-                }
+        rights: seL4_CapRights,
+        cache_attributes: VmAttributes,
+        can_use_dev: bool,
+    ) -> Result<seL4_Word, Error> {
+        let options = MapOptions {
+            rights: rights_bits(&rights),
+            cache_attributes,
+            can_use_dev,
+            large_pages: false,
+        };
+        self.new_pages(paddr, num_pages, size_bits, &options)
+    }
+
+    /// As vspace_new_pages_at(), but larger frames are used wherever the
+    /// vaddr, 'paddr' and size are aligned for them, the vaddr is picked
+    /// to allow it.
+    ///
+    /// The pages can then only be unmapped in ranges covering whole larger
+    /// frames, and vspace_get_cap() gives the larger frame.
+    pub fn vspace_new_large_pages_at(
+        &mut self,
+        paddr: Option<seL4_Word>,
+        num_pages: usize,
+        size_bits: usize,
+        rights: seL4_CapRights,
+        cache_attributes: VmAttributes,
+        can_use_dev: bool,
+    ) -> Result<seL4_Word, Error> {
+        let options = MapOptions {
+            rights: rights_bits(&rights),
+            cache_attributes,
+            can_use_dev,
+            large_pages: true,
+        };
+        self.new_pages(paddr, num_pages, size_bits, &options)
+    }
+
+    /// Map new pages at a newly allocated vaddr, aligned for larger frames
+    /// if the options ask for them.
+    fn new_pages(
+        &mut self,
+        paddr: Option<seL4_Word>,
+        num_pages: usize,
+        size_bits: usize,
+        options: &MapOptions,
+    ) -> Result<seL4_Word, Error> {
+        let size = num_pages as seL4_Word * (1 << size_bits) as seL4_Word;
+        let align_bits = if options.large_pages {
+            frame_bits(0, paddr, size, size_bits)
+        } else {
+            size_bits
+        };
+        let vaddr = self.vspace_alloc_range(size, align_bits)?;
+        if let Err(e) = self.new_pages_at_vaddr(vaddr, paddr, num_pages, size_bits, options) {
+            self.vspace_free_range(vaddr, size)?;
+            return Err(e);
+        }
+
+        Ok(vaddr)
+    }
+
+    /// Set aside 'size' bytes (rounded up to whole pages) of virtual address
+    /// space, for pages mapped later with vspace_new_pages_at_vaddr().
+    ///
+    /// Pages mapped into the reservation get 'rights' and
+    /// 'cache_attributes'.
+    pub fn vspace_reserve_range(
+        &mut self,
+        size: usize,
+        rights: seL4_CapRights,
+        cache_attributes: VmAttributes,
+    ) -> Result<Reservation, Error> {
+        let size = page_round_up(size as _);
+        let index = self.free_reservation_index()?;
+        let vaddr = self.vspace_alloc_range(size, seL4_PageBits as _)?;
+
+        Ok(self.add_reservation(index, vaddr, size, &rights, cache_attributes))
+    }
+
+    /// As vspace_reserve_range(), at a page aligned 'vaddr' that isn't
+    /// mapped or reserved yet.
+    pub fn vspace_reserve_range_at(
+        &mut self,
+        vaddr: seL4_Word,
+        size: usize,
+        rights: seL4_CapRights,
+        cache_attributes: VmAttributes,
+    ) -> Result<Reservation, Error> {
+        if vaddr != page_round_up(vaddr) {
+            return Err(Error::Misaligned(vaddr));
+        }
+
+        let size = page_round_up(size as _);
+        let index = self.free_reservation_index()?;
+        self.vspace_alloc_range_at(vaddr, size)?;
+
+        Ok(self.add_reservation(index, vaddr, size, &rights, cache_attributes))
+    }
+
+    /// Release a reservation, its range becomes available to other mappings.
+    ///
+    /// Pages mapped into it have to be unmapped first.
+    pub fn vspace_free_reservation(&mut self, reservation: Reservation) -> Result<(), Error> {
+        let index = self.reservation_index(&reservation)?;
+        let range = self.reservations[index].range;
+
+        if let Some(page) = self.vspace_pages(range.start, range.size as _).first() {
+            return Err(Error::StillMapped(page.vaddr));
+        }
+
+        self.vspace_free_range(range.start, range.size)?;
+        self.reservations[index].in_use = false;
+
+        Ok(())
+    }
+
+    /// Map 'num_pages' new pages of 2^'size_bits' at 'vaddr', which has to
+    /// be inside 'reservation'.
+    pub fn vspace_new_pages_at_vaddr(
+        &mut self,
+        reservation: &Reservation,
+        vaddr: seL4_Word,
+        num_pages: usize,
+        size_bits: usize,
+    ) -> Result<(), Error> {
+        let index = self.reservation_index(reservation)?;
+        let entry = self.reservations[index];
+        let size = num_pages as seL4_Word * (1 << size_bits) as seL4_Word;
+
+        if !entry.range.contains(vaddr) || ((vaddr + size) > entry.range.end()) {
+            return Err(Error::NotReserved(vaddr));
+        }
+        if vaddr & ((1 << size_bits) - 1) != 0 {
+            return Err(Error::Misaligned(vaddr));
+        }
+        if let Some(page) = self.vspace_pages(vaddr, size as _).first() {
+            return Err(Error::VaddrInUse(page.vaddr));
+        }
+
+        let options = MapOptions {
+            rights: entry.rights,
+            cache_attributes: entry.cache_attributes,
+            can_use_dev: false,
+            large_pages: false,
+        };
+        self.new_pages_at_vaddr(vaddr, None, num_pages, size_bits, &options)
+    }
+
+    /// Map new pages at 'vaddr', see vspace_new_pages_at() and
+    /// vspace_new_large_pages_at().
+    ///
+    /// Nothing is left mapped if any of the pages can't be.
+    fn new_pages_at_vaddr(
+        &mut self,
+        vaddr: seL4_Word,
+        paddr: Option<seL4_Word>,
+        num_pages: usize,
+        size_bits: usize,
+        options: &MapOptions,
+    ) -> Result<(), Error> {
+        let size = num_pages as seL4_Word * (1 << size_bits) as seL4_Word;
+        let mut offset = 0;
+
+        while offset < size {
+            let page_vaddr = vaddr + offset;
+            let page_paddr = paddr.map(|paddr| paddr + offset);
+            let largest = if options.large_pages {
+                frame_bits(page_vaddr, page_paddr, size - offset, size_bits)
             } else {
-                self.vka_alloc_frame(size_bits)?
+                size_bits
             };
 
-            self.map_page(
-                frame_obj.cptr,
-                page_vaddr,
-                //rights,
-                unsafe { seL4_CapRights_new(1, 1, 1) },
-                cache_attributes,
-            )?;
+            // Fall back to smaller frames when there isn't memory for a
+            // larger one, anything else is an error
+            let mut result = Ok(size_bits);
+            for bits in FRAME_SIZES
+                .iter()
+                .cloned()
+                .filter(|&bits| (bits > size_bits) && (bits <= largest))
+                .chain(iter::once(size_bits))
+            {
+                result = self
+                    .new_page_at_vaddr(page_vaddr, page_paddr, bits, options)
+                    .map(|_| bits);
+                if result != Err(Error::ResourceExhausted) {
+                    break;
+                }
+            }
 
-            if page == 0 {
-                first_cap = frame_obj.cptr;
+            match result {
+                Ok(bits) => offset += 1 << bits,
+                Err(e) => {
+                    self.unmap_range(vaddr, offset, true)?;
+                    return Err(e);
+                }
             }
+        }
+
+        Ok(())
+    }
 
-            page_vaddr += 1 << size_bits;
+    fn new_page_at_vaddr(
+        &mut self,
+        vaddr: seL4_Word,
+        paddr: Option<seL4_Word>,
+        size_bits: usize,
+        options: &MapOptions,
+    ) -> Result<(), Error> {
+        if self.num_mappings == self.mappings().len() {
+            return Err(Error::MappingsExhausted);
         }
 
-        // provide cap to the first frame
-        if let Some(cap) = cap {
-            *cap = first_cap;
+        let frame_obj = if let Some(paddr) = paddr {
+            self.vka_alloc_frame_at_maybe_device(size_bits, paddr, options.can_use_dev)?
+        } else {
+            self.vka_alloc_frame_maybe_device(size_bits, options.can_use_dev)?
+        };
+
+        let rights = rights_from_bits(options.rights);
+        if let Err(e) = self.map_page(
+            frame_obj.cptr,
+            vaddr,
+            size_bits,
+            &rights,
+            options.cache_attributes,
+        ) {
+            self.vka_free_object(&frame_obj)?;
+            return Err(e);
         }
+        self.add_mapping(vaddr, &frame_obj, &rights);
 
-        self.last_allocated += num_pages as seL4_Word * (1 << size_bits) as seL4_Word;
+        Ok(())
+    }
 
-        Ok(vaddr)
+    /// Unmap 'num_pages' pages of 2^'size_bits' starting at 'vaddr', mapped
+    /// by one of the vspace_new_* functions or io_map(), and make the range
+    /// available to later mappings.
+    ///
+    /// Larger frames the pages were mapped with have to lie entirely in the
+    /// range.
+    ///
+    /// With 'free_frames' the frames are freed back to their untypeds,
+    /// otherwise their caps stay allocated for the caller.
+    ///
+    /// Nothing is unmapped if there's no room left to track the range as
+    /// unused.
+    pub fn vspace_unmap_pages(
+        &mut self,
+        vaddr: seL4_Word,
+        num_pages: usize,
+        size_bits: usize,
+        free_frames: bool,
+    ) -> Result<(), Error> {
+        let size = num_pages as seL4_Word * (1 << size_bits) as seL4_Word;
+
+        // Check the whole range before touching any of it
+        let mut page_vaddr = vaddr;
+        while page_vaddr < (vaddr + size) {
+            match self.find_mapping(page_vaddr) {
+                Some(i) if self.mappings()[i].end() <= (vaddr + size) => {
+                    page_vaddr = self.mappings()[i].end()
+                }
+                Some(_) => return Err(Error::Misaligned(vaddr + size)),
+                None => return Err(Error::NotMapped(page_vaddr)),
+            }
+        }
+
+        // Pages in a reservation leave the range reserved
+        let free_range = self.find_reservation(vaddr).is_none();
+        if free_range {
+            self.check_free_range(vaddr, size)?;
+        }
+
+        self.unmap_range(vaddr, size, free_frames)?;
+
+        if free_range {
+            self.vspace_free_range(vaddr, size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the pages filling the 'size' bytes at 'vaddr', which are known
+    /// to be in the shadow table, leaving the range allocated.
+    fn unmap_range(
+        &mut self,
+        vaddr: seL4_Word,
+        size: seL4_Word,
+        free_frames: bool,
+    ) -> Result<(), Error> {
+        let mut page_vaddr = vaddr;
+
+        while page_vaddr < (vaddr + size) {
+            let i = self
+                .find_mapping(page_vaddr)
+                .ok_or(Error::NotMapped(page_vaddr))?;
+            let frame = self.unmap_mapping(i)?;
+
+            if free_frames {
+                self.vka_free_object(&frame)?;
+            }
+            page_vaddr += 1 << frame.size_bits;
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the page at index 'i' of the shadow table and drop it from
+    /// the table, returning its frame.
+    fn unmap_mapping(&mut self, i: usize) -> Result<VkaObject, Error> {
+        let page = self.mappings()[i];
+
+        let err: seL4_Error = self.kernel.page_unmap(page.frame.cptr);
+        Error::check(Operation::PageUnmap, err)?;
+        self.remove_mapping(i);
+        self.release_paging_structures(page.vaddr, page.size_bits())?;
+
+        Ok(page.frame)
+    }
+
+    /// Track the pages mapped by the vspace functions in 'table' instead of
+    /// the built-in table of MAX_MAPPINGS entries, so more of them can be
+    /// mapped.
+    ///
+    /// The pages mapped so far are copied over, Error::MappingsExhausted if
+    /// they don't fit.
+    pub fn vspace_set_mappings_table(
+        &mut self,
+        table: &'static mut [MappedPage],
+    ) -> Result<(), Error> {
+        if table.len() < self.num_mappings {
+            return Err(Error::MappingsExhausted);
+        }
+
+        table[..self.num_mappings].copy_from_slice(&self.mappings()[..self.num_mappings]);
+        self.mappings_table = Some(table);
+
+        Ok(())
+    }
+
+    /// Unmap pages and free their frames, see vspace_unmap_pages().
+    pub fn vspace_free_pages(
+        &mut self,
+        vaddr: seL4_Word,
+        num_pages: usize,
+        size_bits: usize,
+    ) -> Result<(), Error> {
+        self.vspace_unmap_pages(vaddr, num_pages, size_bits, true)
+    }
+
+    /// Cap to the frame mapped at 'vaddr', which can be anywhere in the
+    /// page.
+    pub fn vspace_get_cap(&self, vaddr: seL4_Word) -> Option<seL4_CPtr> {
+        self.vspace_get_page(vaddr).map(|page| page.cap())
+    }
+
+    /// The page mapped at 'vaddr', which can be anywhere in the page.
+    pub fn vspace_get_page(&self, vaddr: seL4_Word) -> Option<&MappedPage> {
+        self.vspace_pages(vaddr, 1).first()
+    }
+
+    /// The page the frame 'cap' is mapped as, if the vspace mapped it
+    pub(crate) fn vspace_mapped_page(&self, cap: seL4_CPtr) -> Option<&MappedPage> {
+        self.mappings()[..self.num_mappings]
+            .iter()
+            .find(|page| page.cap() == cap)
+    }
+
+    /// Every page mapped in the 'size' bytes at 'vaddr', in address order.
+    pub fn vspace_pages(&self, vaddr: seL4_Word, size: usize) -> &[MappedPage] {
+        let pages = &self.mappings()[..self.num_mappings];
+        let first = pages.partition_point(|page| page.end() <= vaddr);
+        let last = pages.partition_point(|page| page.vaddr < (vaddr + size as seL4_Word));
+
+        &pages[first..last.max(first)]
+    }
+
+    /// Find 'size' bytes of unused virtual address space aligned to
+    /// 2^'align_bits', reusing unmapped ranges before growing the vspace.
+    fn vspace_alloc_range(
+        &mut self,
+        size: seL4_Word,
+        align_bits: usize,
+    ) -> Result<seL4_Word, Error> {
+        let align = |vaddr: seL4_Word| (vaddr + (1 << align_bits) - 1) & !((1 << align_bits) - 1);
+
+        let start = self.vspace_holes[..self.num_vspace_holes]
+            .iter()
+            .map(|hole| (align(hole.start), hole.end()))
+            .find(|&(start, end)| (start + size) <= end)
+            .map(|(start, _)| start)
+            .unwrap_or_else(|| align(self.last_allocated));
+        self.vspace_alloc_range_at(start, size)?;
+
+        Ok(start)
+    }
+
+    /// Give back a range from vspace_alloc_range(), merging it with its
+    /// neighbours.
+    ///
+    /// Nothing is freed if the range needs a hole of its own and there's
+    /// no room left to track it.
+    fn vspace_free_range(&mut self, start: seL4_Word, size: seL4_Word) -> Result<(), Error> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        self.check_free_range(start, size)?;
+
+        let mut range = VRange { start, size };
+        let mut i = self.vspace_holes[..self.num_vspace_holes]
+            .iter()
+            .position(|hole| hole.start > start)
+            .unwrap_or(self.num_vspace_holes);
+
+        if (i > 0) && ((self.vspace_holes[i - 1].start + self.vspace_holes[i - 1].size) == start) {
+            i -= 1;
+            range.start = self.vspace_holes[i].start;
+            range.size += self.vspace_holes[i].size;
+            self.remove_vspace_hole(i);
+        }
+
+        if (i < self.num_vspace_holes) && ((range.start + range.size) == self.vspace_holes[i].start)
+        {
+            range.size += self.vspace_holes[i].size;
+            self.remove_vspace_hole(i);
+        }
+
+        if (range.start + range.size) == self.last_allocated {
+            self.last_allocated = range.start;
+            return Ok(());
+        }
+
+        self.vspace_holes
+            .copy_within(i..self.num_vspace_holes, i + 1);
+        self.vspace_holes[i] = range;
+        self.num_vspace_holes += 1;
+
+        Ok(())
+    }
+
+    /// Check vspace_free_range() has room to track the range, before
+    /// anything that can't be undone.
+    fn check_free_range(&self, start: seL4_Word, size: seL4_Word) -> Result<(), Error> {
+        let holes = &self.vspace_holes[..self.num_vspace_holes];
+        let i = holes
+            .iter()
+            .position(|hole| hole.start > start)
+            .unwrap_or(holes.len());
+
+        let merges = ((i > 0) && (holes[i - 1].end() == start))
+            || ((i < holes.len()) && ((start + size) == holes[i].start))
+            || ((start + size) == self.last_allocated);
+        if (size != 0) && !merges && (holes.len() == MAX_VSPACE_HOLES) {
+            return Err(Error::VspaceHolesExhausted);
+        }
+
+        Ok(())
+    }
+
+    /// Take the range at 'start' out of the unused virtual address space.
+    fn vspace_alloc_range_at(&mut self, start: seL4_Word, size: seL4_Word) -> Result<(), Error> {
+        if start < VSPACE_START {
+            return Err(Error::VaddrInUse(start));
+        }
+
+        if start >= self.last_allocated {
+            // Whatever is skipped over stays available
+            let top = self.last_allocated;
+            self.last_allocated = start + size;
+            if let Err(e) = self.vspace_free_range(top, start - top) {
+                self.last_allocated = top;
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        let i = self.vspace_holes[..self.num_vspace_holes]
+            .iter()
+            .position(|hole| hole.contains(start) && ((start + size) <= hole.end()))
+            .ok_or(Error::VaddrInUse(start))?;
+        let hole = self.vspace_holes[i];
+
+        // Splitting the hole in two takes another one
+        let splits = (start > hole.start) && ((start + size) < hole.end());
+        if splits && (self.num_vspace_holes == MAX_VSPACE_HOLES) {
+            return Err(Error::VspaceHolesExhausted);
+        }
+
+        self.remove_vspace_hole(i);
+        self.vspace_free_range(hole.start, start - hole.start)?;
+        self.vspace_free_range(start + size, hole.end() - (start + size))?;
+
+        Ok(())
+    }
+
+    fn remove_vspace_hole(&mut self, i: usize) {
+        self.vspace_holes
+            .copy_within((i + 1)..self.num_vspace_holes, i);
+        self.num_vspace_holes -= 1;
+    }
+
+    fn free_reservation_index(&self) -> Result<usize, Error> {
+        self.reservations
+            .iter()
+            .position(|r| !r.in_use)
+            .ok_or(Error::ReservationsExhausted)
+    }
+
+    fn add_reservation(
+        &mut self,
+        index: usize,
+        start: seL4_Word,
+        size: seL4_Word,
+        rights: &seL4_CapRights,
+        cache_attributes: VmAttributes,
+    ) -> Reservation {
+        self.reservations[index] = ReservationEntry {
+            range: VRange { start, size },
+            rights: rights_bits(rights),
+            cache_attributes,
+            in_use: true,
+        };
+
+        Reservation {
+            index,
+            vaddr: start,
+            size,
+        }
+    }
+
+    /// Index of a reservation handed out by vspace_reserve_range*() that
+    /// hasn't been freed
+    fn reservation_index(&self, reservation: &Reservation) -> Result<usize, Error> {
+        match self.reservations.get(reservation.index) {
+            Some(r) if r.in_use && (r.range.start == reservation.vaddr) => Ok(reservation.index),
+            _ => Err(Error::UnknownReservation),
+        }
+    }
+
+    fn find_reservation(&self, vaddr: seL4_Word) -> Option<usize> {
+        self.reservations
+            .iter()
+            .position(|r| r.in_use && r.range.contains(vaddr))
+    }
+
+    /// The shadow page table, see vspace_set_mappings_table()
+    fn mappings(&self) -> &[MappedPage] {
+        match self.mappings_table {
+            Some(ref table) => table,
+            None => &self.mappings,
+        }
+    }
+
+    fn mappings_mut(&mut self) -> &mut [MappedPage] {
+        match self.mappings_table {
+            Some(ref mut table) => table,
+            None => &mut self.mappings,
+        }
+    }
+
+    /// Record a mapped page in the shadow table, keeping it sorted by
+    /// address.
+    fn add_mapping(&mut self, vaddr: seL4_Word, frame: &VkaObject, rights: &seL4_CapRights) {
+        let i = self.mappings()[..self.num_mappings].partition_point(|page| page.vaddr < vaddr);
+
+        let num_mappings = self.num_mappings;
+        self.mappings_mut().copy_within(i..num_mappings, i + 1);
+        self.mappings_mut()[i] = MappedPage {
+            vaddr,
+            frame: *frame,
+            rights: rights_bits(rights),
+        };
+        self.num_mappings += 1;
+    }
+
+    /// Index of the page mapped at exactly 'vaddr'
+    fn find_mapping(&self, vaddr: seL4_Word) -> Option<usize> {
+        self.mappings()[..self.num_mappings]
+            .binary_search_by_key(&vaddr, |page| page.vaddr)
+            .ok()
+    }
+
+    fn remove_mapping(&mut self, i: usize) {
+        let num_mappings = self.num_mappings;
+        self.mappings_mut().copy_within((i + 1)..num_mappings, i);
+        self.num_mappings -= 1;
     }
 
     fn map_page(
         &mut self,
         cap: seL4_CPtr,
         vaddr: seL4_Word,
-        _rights: seL4_CapRights,
-        cache_attributes: seL4_ARM_VMAttributes,
+        size_bits: usize,
+        rights: &seL4_CapRights,
+        cache_attributes: VmAttributes,
     ) -> Result<(), Error> {
-        let map_err: seL4_Error = unsafe {
-            seL4_ARM_Page_Map(
+        let mut err: seL4_Error = self.kernel.page_map(
+            cap,
+            self.vspace_root,
+            vaddr,
+            copy_rights(rights),
+            cache_attributes,
+        );
+
+        // Create the missing paging structures one at a time, the kernel
+        // tells us which level it couldn't find, from the top down
+        let mut result = Ok(());
+        let mut created = None;
+        for _ in 0..PAGING_LEVELS.len() {
+            if err != seL4_Error_seL4_FailedLookup {
+                break;
+            }
+            let bits = self.kernel.mapping_failed_lookup_level();
+            let level = match PAGING_LEVELS
+                .iter()
+                .position(|level| level.vaddr_bits as seL4_Word == bits)
+            {
+                Some(level) => level,
+                None => break,
+            };
+            result = self.map_paging_structure(level, vaddr, cache_attributes);
+            if result.is_err() {
+                break;
+            }
+            created = Some(level);
+
+            err = self.kernel.page_map(
                 cap,
-                self.page_directory,
+                self.vspace_root,
                 vaddr,
-                //rights,
-                seL4_CapRights_new(1, 1, 1),
+                copy_rights(rights),
                 cache_attributes,
-            )
-        };
-
-        if map_err != 0 {
-            // create a page table
-            // TODO - is leaky
-            let pt_obj = self.vka_alloc_page_table()?;
-            self.page_table = pt_obj.cptr;
-
-            // map the page table
-            let err: seL4_Error = unsafe {
-                seL4_ARM_PageTable_Map(
-                    self.page_table,
-                    self.page_directory,
-                    vaddr,
-                    cache_attributes,
-                )
-            };
+            );
+        }
 
-            if err != 0 {
-                return Err(Error::Other);
+        if let Err(e) = result.and_then(|_| Error::check(Operation::PageMap, err)) {
+            // Don't leave the structures we created behind, empty
+            if let Some(level) = created {
+                self.free_empty_paging_structures(level, vaddr)?;
             }
+            return Err(e);
+        }
 
-            // map the frame in
-            let err: seL4_Error = unsafe {
-                seL4_ARM_Page_Map(
-                    cap,
-                    self.page_directory,
-                    vaddr,
-                    //rights,
-                    seL4_CapRights_new(1, 1, 1),
-                    cache_attributes,
-                )
-            };
+        if let Some(i) = leaf_level(size_bits).and_then(|l| self.find_paging_structure(l, vaddr)) {
+            self.paging_structures[i].num_children += 1;
+        }
 
-            if err != 0 {
-                return Err(Error::Other);
-            }
+        Ok(())
+    }
+
+    /// Allocate and map the paging structure at 'level' covering 'vaddr'.
+    fn map_paging_structure(
+        &mut self,
+        level: usize,
+        vaddr: seL4_Word,
+        cache_attributes: VmAttributes,
+    ) -> Result<(), Error> {
+        if self.num_paging_structures == MAX_PAGING_STRUCTURES {
+            return Err(Error::PagingStructuresExhausted);
+        }
+
+        let obj_type = PAGING_LEVELS[level].obj_type;
+        let obj_size_bits = self.vka_get_object_size(obj_type, 0)?;
+        let obj = self.vka_alloc_object(obj_type, obj_size_bits)?;
+
+        let err: seL4_Error = self.kernel.paging_structure_map(
+            obj_type,
+            obj.cptr,
+            self.vspace_root,
+            vaddr,
+            cache_attributes,
+        );
+        if let Err(e) = Error::check(Operation::PageTableMap, err) {
+            self.vka_free_object(&obj)?;
+            return Err(e);
         }
 
+        self.add_paging_structure(level, vaddr, &obj);
+
         Ok(())
     }
+
+    /// A page of 2^'size_bits' at 'vaddr' was unmapped, unmap and free the
+    /// paging structures that are now empty, walking up from the bottom
+    /// level.
+    fn release_paging_structures(
+        &mut self,
+        vaddr: seL4_Word,
+        size_bits: usize,
+    ) -> Result<(), Error> {
+        let level = match leaf_level(size_bits) {
+            Some(level) => level,
+            None => return Ok(()),
+        };
+
+        if let Some(i) = self.find_paging_structure(level, vaddr) {
+            self.paging_structures[i].num_children -= 1;
+            self.free_empty_paging_structures(level, vaddr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unmap and free our paging structure at 'level' covering 'vaddr' if
+    /// nothing is mapped into it, then its parents as they become empty.
+    fn free_empty_paging_structures(
+        &mut self,
+        mut level: usize,
+        vaddr: seL4_Word,
+    ) -> Result<(), Error> {
+        while let Some(i) = self.find_paging_structure(level, vaddr) {
+            if self.paging_structures[i].num_children != 0 {
+                break;
+            }
+
+            let obj = self.paging_structures[i].obj;
+            let err: seL4_Error = self
+                .kernel
+                .paging_structure_unmap(PAGING_LEVELS[level].obj_type, obj.cptr);
+            Error::check(Operation::PageTableUnmap, err)?;
+
+            self.num_paging_structures -= 1;
+            self.paging_structures[i] = self.paging_structures[self.num_paging_structures];
+            self.vka_free_object(&obj)?;
+
+            if level == 0 {
+                break;
+            }
+            level -= 1;
+            if let Some(parent) = self.find_paging_structure(level, vaddr) {
+                self.paging_structures[parent].num_children -= 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_paging_structure(&mut self, level: usize, vaddr: seL4_Word, obj: &VkaObject) {
+        if level > 0 {
+            if let Some(parent) = self.find_paging_structure(level - 1, vaddr) {
+                self.paging_structures[parent].num_children += 1;
+            }
+        }
+
+        self.paging_structures[self.num_paging_structures] = PagingStructure {
+            level,
+            vaddr: vaddr & !((1 << PAGING_LEVELS[level].vaddr_bits) - 1),
+            obj: *obj,
+            num_children: 0,
+        };
+        self.num_paging_structures += 1;
+    }
+
+    /// Paging structure at 'level' we allocated that covers 'vaddr'
+    fn find_paging_structure(&self, level: usize, vaddr: seL4_Word) -> Option<usize> {
+        let base = vaddr & !((1 << PAGING_LEVELS[level].vaddr_bits) - 1);
+
+        self.paging_structures[..self.num_paging_structures]
+            .iter()
+            .position(|ps| (ps.level == level) && (ps.vaddr == base))
+    }
+}
+
+/// Size (in bits) of the largest frame, no smaller than 2^'min_bits', that
+/// 'vaddr' and 'paddr' are aligned to and fits in 'size' bytes
+fn frame_bits(
+    vaddr: seL4_Word,
+    paddr: Option<seL4_Word>,
+    size: seL4_Word,
+    min_bits: usize,
+) -> usize {
+    let aligned = |addr: seL4_Word, bits: usize| addr & ((1 << bits) - 1) == 0;
+
+    FRAME_SIZES
+        .iter()
+        .cloned()
+        .find(|&bits| {
+            (bits >= min_bits)
+                && ((1 << bits) <= size)
+                && aligned(vaddr, bits)
+                && paddr.map_or(true, |paddr| aligned(paddr, bits))
+        })
+        .unwrap_or(min_bits)
+}
+
+/// Index of the lowest paging level a page of 2^'size_bits' is mapped
+/// into, None if it goes straight into the vspace root
+fn leaf_level(size_bits: usize) -> Option<usize> {
+    PAGING_LEVELS
+        .iter()
+        .rposition(|level| level.vaddr_bits > size_bits)
+}
+
+/// A page mapped by one of the vspace functions, an entry in the vspace's
+/// shadow page table.
+#[derive(Clone, Copy, Debug)]
+pub struct MappedPage {
+    vaddr: seL4_Word,
+    frame: VkaObject,
+    /// seL4_CapRights bits it was mapped with
+    rights: seL4_Word,
+}
+
+impl MappedPage {
+    /// An empty entry, for building a table for vspace_set_mappings_table()
+    pub const fn unused() -> MappedPage {
+        MappedPage {
+            vaddr: 0,
+            frame: VkaObject::new(),
+            rights: 0,
+        }
+    }
+
+    /// Start of the page
+    pub fn vaddr(&self) -> seL4_Word {
+        self.vaddr
+    }
+
+    /// Cap to the frame
+    pub fn cap(&self) -> seL4_CPtr {
+        self.frame.cptr
+    }
+
+    /// Size (in bits) of the page
+    pub fn size_bits(&self) -> usize {
+        self.frame.size_bits as _
+    }
+
+    /// Rights the page was mapped with
+    pub fn rights(&self) -> seL4_CapRights {
+        rights_from_bits(self.rights)
+    }
+
+    /// The frame object, as allocated from the vka
+    pub fn frame(&self) -> &VkaObject {
+        &self.frame
+    }
+
+    fn end(&self) -> seL4_Word {
+        self.vaddr + (1 << self.frame.size_bits)
+    }
+}
+
+/// A range of virtual address space set aside by vspace_reserve_range(),
+/// like libsel4vspace's reservation_t.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    index: usize,
+    vaddr: seL4_Word,
+    size: seL4_Word,
+}
+
+impl Reservation {
+    /// Start of the reserved range
+    pub fn vaddr(&self) -> seL4_Word {
+        self.vaddr
+    }
+
+    /// Size of the reserved range, a whole number of pages
+    pub fn size(&self) -> usize {
+        self.size as _
+    }
+}
+
+/// How new pages are mapped, and what backs them
+#[derive(Clone, Copy, Debug)]
+struct MapOptions {
+    /// seL4_CapRights bits to map the pages with
+    rights: seL4_Word,
+    cache_attributes: VmAttributes,
+    /// The frames may come from device memory
+    can_use_dev: bool,
+    /// Use larger frames where the pages are aligned for them
+    large_pages: bool,
+}
+
+impl VRange {
+    fn end(&self) -> seL4_Word {
+        self.start + self.size
+    }
+
+    fn contains(&self, vaddr: seL4_Word) -> bool {
+        (vaddr >= self.start) && (vaddr < self.end())
+    }
+}
+
+fn page_round_up(size: seL4_Word) -> seL4_Word {
+    let page_size: seL4_Word = 1 << seL4_PageBits;
+    (size + page_size - 1) & !(page_size - 1)
+}
+
+/// seL4_CapRights isn't Copy, keep the bits instead
+fn rights_bits(rights: &seL4_CapRights) -> seL4_Word {
+    rights.words[0]
+}
+
+fn rights_from_bits(bits: seL4_Word) -> seL4_CapRights {
+    seL4_CapRights { words: [bits] }
+}
+
+fn copy_rights(rights: &seL4_CapRights) -> seL4_CapRights {
+    rights_from_bits(rights_bits(rights))
+}
+
+#[cfg(all(test, feature = "aarch32"))]
+mod tests {
+    use arch::{DEFAULT_VM_ATTRIBUTES, DEVICE_VM_ATTRIBUTES};
+    use sel4_sys::*;
+    use sim;
+    use std::vec::Vec;
+    use {Error, KernelError, Operation};
+    use {MAX_VSPACE_HOLES, VSPACE_START};
+
+    const RAM: &[sim::SimUntyped] = &[
+        (0x1000_0000, 12, false),
+        (0x1000_1000, 12, false),
+        (0x1000_2000, 12, false),
+        (0x1000_3000, 12, false),
+    ];
+
+    #[test]
+    fn new_pages_are_mapped_to_their_frames() {
+        let mut alloc = sim::allocator(RAM);
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                2,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+
+        assert_eq!(vaddr, VSPACE_START);
+        for page in 0..2 {
+            let page_vaddr = vaddr + (page << seL4_PageBits);
+            let cap = alloc.vspace_get_cap(page_vaddr + 0x10).unwrap();
+            assert_eq!(
+                alloc.kernel.translate(page_vaddr),
+                alloc.kernel.cap_paddr(cap)
+            );
+        }
+        assert!(alloc
+            .kernel
+            .translate(vaddr + (2 << seL4_PageBits))
+            .is_none());
+        assert_eq!(alloc.vspace_get_cap(vaddr + (2 << seL4_PageBits)), None);
+    }
+
+    #[test]
+    fn shadow_table_records_every_page() {
+        let mut alloc = sim::allocator(RAM);
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+
+        let res = alloc
+            .vspace_reserve_range(
+                4 * page_size as usize,
+                unsafe { seL4_CapRights_new(0, 1, 0) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        let vaddr = res.vaddr();
+
+        // Mapped out of order, walked in address order
+        alloc
+            .vspace_new_pages_at_vaddr(&res, vaddr + (2 * page_size), 2, seL4_PageBits as _)
+            .unwrap();
+        alloc
+            .vspace_new_pages_at_vaddr(&res, vaddr, 1, seL4_PageBits as _)
+            .unwrap();
+
+        let pages = alloc.vspace_pages(vaddr + 0x10, res.size());
+        let vaddrs: Vec<_> = pages.iter().map(|page| page.vaddr()).collect();
+        assert_eq!(
+            vaddrs,
+            vec![vaddr, vaddr + (2 * page_size), vaddr + (3 * page_size)]
+        );
+
+        for page in pages {
+            assert_eq!(page.size_bits(), seL4_PageBits as usize);
+            assert_eq!(
+                alloc.kernel.cap_type(page.cap()),
+                Some(_object_seL4_ARM_SmallPageObject)
+            );
+            assert_eq!(
+                unsafe { seL4_CapRights_get_capAllowWrite(page.rights()) },
+                0
+            );
+        }
+
+        assert!(alloc.vspace_pages(vaddr + page_size, 0x10).is_empty());
+    }
+
+    #[test]
+    fn new_pages_only_use_device_memory_when_allowed() {
+        // Only enough RAM for the page table
+        let untypeds = [(0x1000_0000, 10, false), (0x0209_8000, 12, true)];
+        let mut alloc = sim::allocator(&untypeds);
+
+        assert_eq!(
+            alloc.vspace_new_pages_at(
+                None,
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEVICE_VM_ATTRIBUTES,
+                false,
+            ),
+            Err(Error::ResourceExhausted)
+        );
+
+        // Asking for the device's paddr doesn't get around it
+        assert_eq!(
+            alloc.vspace_new_pages_at(
+                Some(0x0209_8000),
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEVICE_VM_ATTRIBUTES,
+                false,
+            ),
+            Err(Error::ResourceExhausted)
+        );
+        assert_eq!(alloc.num_slots_used, 0);
+
+        let vaddr = alloc
+            .vspace_new_pages_at(
+                Some(0x0209_8000),
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEVICE_VM_ATTRIBUTES,
+                true,
+            )
+            .unwrap();
+        assert_eq!(alloc.kernel.translate(vaddr), Some(0x0209_8000));
+    }
+
+    #[test]
+    fn map_page_creates_a_page_table_on_demand() {
+        let mut alloc = sim::allocator(RAM);
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+
+        alloc.vspace_new_ipc_buffer().unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 1);
+
+        // Same 1M region, no new page table needed
+        alloc.vspace_new_ipc_buffer().unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 1);
+    }
+
+    #[test]
+    fn page_table_is_freed_with_its_last_page() {
+        let mut alloc = sim::allocator(RAM);
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                2,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 1);
+
+        alloc
+            .vspace_free_pages(vaddr, 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 1);
+
+        alloc
+            .vspace_free_pages(vaddr + (1 << seL4_PageBits), 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn map_errors_other_than_a_missing_page_table_are_returned() {
+        let mut alloc = sim::allocator(RAM);
+
+        // The sim kernel window starts at 0xe000_0000
+        let res = alloc
+            .vspace_reserve_range_at(
+                0xe000_0000,
+                1 << seL4_PageBits,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+
+        assert_eq!(
+            alloc.vspace_new_pages_at_vaddr(&res, res.vaddr(), 1, seL4_PageBits as _),
+            Err(Error::Kernel(
+                Operation::PageMap,
+                KernelError::InvalidArgument
+            ))
+        );
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn free_pages_returns_the_frames_and_the_range() {
+        let mut alloc = sim::allocator(RAM);
+
+        // Keep the page table in use
+        alloc.vspace_new_ipc_buffer().unwrap();
+        let num_slots = alloc.num_slots_used;
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                2,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        alloc
+            .vspace_free_pages(vaddr, 2, seL4_PageBits as _)
+            .unwrap();
+
+        assert_eq!(alloc.num_slots_used, num_slots);
+        assert_eq!(alloc.kernel.translate(vaddr), None);
+        assert_eq!(alloc.kernel.translate(vaddr + (1 << seL4_PageBits)), None);
+        assert_eq!(alloc.last_allocated, vaddr);
+
+        // The range and frames are used again
+        assert_eq!(alloc.vspace_new_ipc_buffer().unwrap(), vaddr);
+    }
+
+    #[test]
+    fn free_pages_is_refused_when_its_range_cant_be_tracked() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+
+        let vaddrs: Vec<_> = (0..((2 * MAX_VSPACE_HOLES) + 2))
+            .map(|_| alloc.vspace_new_ipc_buffer().unwrap())
+            .collect();
+
+        // Every other page leaves a hole of its own
+        for &vaddr in vaddrs.iter().step_by(2).take(MAX_VSPACE_HOLES) {
+            alloc
+                .vspace_free_pages(vaddr, 1, seL4_PageBits as _)
+                .unwrap();
+        }
+        assert_eq!(alloc.num_vspace_holes, MAX_VSPACE_HOLES);
+
+        let vaddr = vaddrs[2 * MAX_VSPACE_HOLES];
+        assert_eq!(
+            alloc.vspace_free_pages(vaddr, 1, seL4_PageBits as _),
+            Err(Error::VspaceHolesExhausted)
+        );
+        assert!(alloc.kernel.translate(vaddr).is_some());
+        assert!(alloc.vspace_get_cap(vaddr).is_some());
+
+        // Joining two holes makes room
+        alloc
+            .vspace_free_pages(vaddrs[1], 1, seL4_PageBits as _)
+            .unwrap();
+        alloc
+            .vspace_free_pages(vaddr, 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.num_vspace_holes, MAX_VSPACE_HOLES);
+    }
+
+    #[test]
+    fn unmap_pages_can_keep_the_frames() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 16, false)]);
+
+        let first = alloc.vspace_new_ipc_buffer().unwrap();
+        let vaddr = alloc.vspace_new_ipc_buffer().unwrap();
+        let cap = alloc.vspace_get_cap(vaddr).unwrap();
+        alloc.vspace_new_ipc_buffer().unwrap();
+
+        alloc
+            .vspace_unmap_pages(vaddr, 1, seL4_PageBits as _, false)
+            .unwrap();
+        assert_eq!(alloc.kernel.translate(vaddr), None);
+        assert_eq!(alloc.vspace_get_cap(vaddr), None);
+        assert_eq!(
+            alloc.kernel.cap_type(cap),
+            Some(_object_seL4_ARM_SmallPageObject)
+        );
+
+        // Neighbouring holes are merged and reused
+        alloc
+            .vspace_unmap_pages(first, 1, seL4_PageBits as _, true)
+            .unwrap();
+        let vaddr = alloc
+            .vspace_new_pages(
+                2,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(vaddr, first);
+    }
+
+    #[test]
+    fn unmap_pages_rejects_pages_that_are_not_mapped() {
+        let mut alloc = sim::allocator(RAM);
+
+        let vaddr = alloc.vspace_new_ipc_buffer().unwrap();
+        let next = vaddr + (1 << seL4_PageBits);
+
+        assert_eq!(
+            alloc.vspace_free_pages(vaddr, 2, seL4_PageBits as _),
+            Err(Error::NotMapped(next))
+        );
+        assert_eq!(
+            alloc.vspace_free_pages(vaddr, 1, seL4_LargePageBits as _),
+            Err(Error::NotMapped(next))
+        );
+
+        // Nothing was unmapped
+        assert!(alloc.kernel.translate(vaddr).is_some());
+    }
+
+    #[test]
+    fn pages_are_mapped_into_reservations() {
+        let mut alloc = sim::allocator(RAM);
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+
+        let res = alloc
+            .vspace_reserve_range(
+                3 * page_size as usize - 1,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(res.vaddr(), VSPACE_START);
+        assert_eq!(res.size(), 3 * page_size as usize);
+
+        // Other mappings go after the reservation
+        let vaddr = alloc.vspace_new_ipc_buffer().unwrap();
+        assert_eq!(vaddr, VSPACE_START + (3 * page_size));
+
+        let mid = res.vaddr() + page_size;
+        alloc
+            .vspace_new_pages_at_vaddr(&res, mid, 1, seL4_PageBits as _)
+            .unwrap();
+        let cap = alloc.vspace_get_cap(mid).unwrap();
+        assert_eq!(alloc.kernel.translate(mid), alloc.kernel.cap_paddr(cap));
+        assert_eq!(alloc.kernel.translate(res.vaddr()), None);
+
+        assert_eq!(
+            alloc.vspace_new_pages_at_vaddr(&res, mid, 1, seL4_PageBits as _),
+            Err(Error::VaddrInUse(mid))
+        );
+        assert_eq!(
+            alloc.vspace_new_pages_at_vaddr(&res, mid, 3, seL4_PageBits as _),
+            Err(Error::NotReserved(mid))
+        );
+        assert_eq!(
+            alloc.vspace_new_pages_at_vaddr(&res, vaddr, 1, seL4_PageBits as _),
+            Err(Error::NotReserved(vaddr))
+        );
+
+        // Unmapping leaves the range reserved
+        alloc.vspace_free_pages(mid, 1, seL4_PageBits as _).unwrap();
+        assert_eq!(alloc.vspace_new_ipc_buffer().unwrap(), vaddr + page_size);
+    }
+
+    #[test]
+    fn reserve_range_at_takes_the_requested_range() {
+        let mut alloc = sim::allocator(RAM);
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+        let at = VSPACE_START + (4 * page_size);
+
+        let res = alloc
+            .vspace_reserve_range_at(
+                at,
+                page_size as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(res.vaddr(), at);
+
+        for vaddr in &[at, at + 0x10] {
+            assert!(alloc
+                .vspace_reserve_range_at(
+                    *vaddr,
+                    page_size as _,
+                    unsafe { seL4_CapRights_new(1, 1, 1) },
+                    DEFAULT_VM_ATTRIBUTES,
+                )
+                .is_err());
+        }
+
+        // The range skipped over is still used
+        assert_eq!(alloc.vspace_new_ipc_buffer().unwrap(), VSPACE_START);
+    }
+
+    #[test]
+    fn free_reservation_releases_the_range() {
+        let mut alloc = sim::allocator(RAM);
+
+        let res = alloc
+            .vspace_reserve_range(
+                1 << seL4_PageBits,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        alloc
+            .vspace_new_pages_at_vaddr(&res, res.vaddr(), 1, seL4_PageBits as _)
+            .unwrap();
+
+        assert_eq!(
+            alloc.vspace_free_reservation(res),
+            Err(Error::StillMapped(res.vaddr()))
+        );
+
+        alloc
+            .vspace_free_pages(res.vaddr(), 1, seL4_PageBits as _)
+            .unwrap();
+        alloc.vspace_free_reservation(res).unwrap();
+
+        assert_eq!(
+            alloc.vspace_free_reservation(res),
+            Err(Error::UnknownReservation)
+        );
+        assert_eq!(alloc.vspace_new_ipc_buffer().unwrap(), res.vaddr());
+    }
+
+    #[test]
+    fn io_map_maps_the_device_frame() {
+        let mut untypeds = RAM.to_vec();
+        untypeds.push((0x0209_8000, 12, true));
+        let mut alloc = sim::allocator(&untypeds);
+
+        let vaddr = alloc.io_map(0x0209_8000, seL4_PageBits as _).unwrap();
+        assert_eq!(alloc.kernel.translate(vaddr + 0x10), Some(0x0209_8010));
+    }
+
+    #[test]
+    fn io_map_maps_the_whole_window() {
+        let mut untypeds = RAM.to_vec();
+        untypeds.push((0x0209_0000, 16, true));
+        let mut alloc = sim::allocator(&untypeds);
+
+        let vaddr = alloc.io_map(0x0209_8000, 14).unwrap();
+        for offset in (0..(1 << 14)).step_by(1 << seL4_PageBits) {
+            assert_eq!(
+                alloc.kernel.translate(vaddr + offset + 0x10),
+                Some(0x0209_8010 + offset)
+            );
+        }
+
+        // Not page aligned, spills over into a second page
+        let vaddr = alloc.io_map(0x0209_0ff0, 5).unwrap();
+        assert_eq!(alloc.kernel.translate(vaddr), Some(0x0209_0ff0));
+        assert_eq!(alloc.kernel.translate(vaddr + 0x18), Some(0x0209_1008));
+    }
+
+    #[test]
+    fn io_map_past_the_device_unmaps_everything() {
+        let mut untypeds = RAM.to_vec();
+        untypeds.push((0x0209_8000, 14, true));
+        let mut alloc = sim::allocator(&untypeds);
+
+        assert_eq!(alloc.io_map(0x0209_8000, 15), Err(Error::ResourceExhausted));
+        assert_eq!(alloc.num_slots_used, 0);
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.last_allocated, VSPACE_START);
+
+        // All of it can be mapped again
+        let vaddr = alloc.io_map(0x0209_8000, 14).unwrap();
+        assert_eq!(vaddr, VSPACE_START);
+    }
+
+    #[test]
+    fn io_map_uses_sections_where_aligned() {
+        let mut untypeds = RAM.to_vec();
+        untypeds.push((0x0200_0000, 21, true));
+        let mut alloc = sim::allocator(&untypeds);
+
+        let vaddr = alloc.io_map(0x0200_0000, 21).unwrap();
+        assert_eq!(vaddr & ((1 << seL4_SectionBits) - 1), 0);
+
+        let pages = alloc.vspace_pages(vaddr, 1 << 21);
+        assert_eq!(pages.len(), 2);
+        assert!(pages
+            .iter()
+            .all(|page| page.size_bits() == seL4_SectionBits as usize));
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(
+            alloc.kernel.translate(vaddr + (1 << 20) + 0x10),
+            Some(0x0210_0010)
+        );
+    }
+
+    #[test]
+    fn new_pages_are_the_size_asked_for() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 17, false)]);
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                16,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+
+        let pages = alloc.vspace_pages(vaddr, 1 << seL4_LargePageBits);
+        assert_eq!(pages.len(), 16);
+        assert!(pages
+            .iter()
+            .all(|page| page.size_bits() == seL4_PageBits as usize));
+
+        // Each of them can be unmapped on its own
+        let cap = alloc.vspace_get_cap(vaddr + page_size).unwrap();
+        assert_eq!(
+            alloc.kernel.cap_size_bits(cap),
+            Some(seL4_PageBits as usize)
+        );
+        alloc
+            .vspace_free_pages(vaddr + page_size, 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.kernel.translate(vaddr + page_size), None);
+        assert!(alloc.kernel.translate(vaddr).is_some());
+    }
+
+    #[test]
+    fn large_pages_are_used_with_aligned_vaddrs() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 17, false)]);
+        let page_size: seL4_Word = 1 << seL4_PageBits;
+
+        let ipc_buffer = alloc.vspace_new_ipc_buffer().unwrap();
+        let vaddr = alloc
+            .vspace_new_large_pages_at(
+                None,
+                16,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+                false,
+            )
+            .unwrap();
+        assert_eq!(vaddr, ipc_buffer + (1 << seL4_LargePageBits));
+
+        let page = alloc.vspace_get_page(vaddr + (15 * page_size)).unwrap();
+        assert_eq!(page.vaddr(), vaddr);
+        assert_eq!(page.size_bits(), seL4_LargePageBits as usize);
+
+        // The range skipped over is used by later small pages
+        assert_eq!(
+            alloc.vspace_new_ipc_buffer().unwrap(),
+            ipc_buffer + page_size
+        );
+
+        // Freed as the small pages it was asked for
+        let num_slots = alloc.num_slots_used;
+        alloc
+            .vspace_free_pages(vaddr, 16, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.num_slots_used, num_slots - 1);
+        assert_eq!(alloc.kernel.translate(vaddr), None);
+    }
+
+    #[test]
+    fn unmap_pages_rejects_part_of_a_large_page() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 17, false)]);
+
+        let vaddr = alloc
+            .vspace_new_large_pages_at(
+                None,
+                16,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(
+            alloc.vspace_free_pages(vaddr, 1, seL4_PageBits as _),
+            Err(Error::Misaligned(vaddr + (1 << seL4_PageBits)))
+        );
+        assert!(alloc.kernel.translate(vaddr).is_some());
+    }
+
+    #[test]
+    fn large_pages_fall_back_to_small_pages() {
+        // Plenty of memory, but no untyped a large page fits in
+        let untypeds: Vec<_> = (0..4)
+            .map(|i| (0x1000_0000 + (i << 15), 15, false))
+            .collect();
+        let mut alloc = sim::allocator(&untypeds);
+
+        let vaddr = alloc
+            .vspace_new_large_pages_at(
+                None,
+                16,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+                false,
+            )
+            .unwrap();
+
+        let pages = alloc.vspace_pages(vaddr, 1 << seL4_LargePageBits);
+        assert_eq!(pages.len(), 16);
+        assert!(pages
+            .iter()
+            .all(|page| page.size_bits() == seL4_PageBits as usize));
+    }
+}
+
+/// The multi-level paging of the other architectures, on the MockKernel
+/// Paging tests that hold for every architecture
+#[cfg(test)]
+mod paging_tests {
+    use super::*;
+    use arch::DEVICE_VM_ATTRIBUTES;
+    use sim::{self, SimKernel};
+    use std::boxed::Box;
+    use std::vec;
+    use {KernelError, ObjectType};
+
+    fn new_page(alloc: &mut Allocator<SimKernel>) -> seL4_Word {
+        alloc
+            .vspace_new_pages(
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn mappings_get_the_requested_rights_and_attributes() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(0, 1, 0) },
+                DEVICE_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        let read_only = unsafe { seL4_CapRights_new(0, 1, 0) }.words[0];
+        assert_eq!(
+            alloc.kernel.mapping_attributes(vaddr),
+            Some((read_only, DEVICE_VM_ATTRIBUTES))
+        );
+
+        let res = alloc
+            .vspace_reserve_range(
+                1 << seL4_PageBits,
+                unsafe { seL4_CapRights_new(0, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        alloc
+            .vspace_new_pages_at_vaddr(&res, res.vaddr(), 1, seL4_PageBits as _)
+            .unwrap();
+        let read_write = unsafe { seL4_CapRights_new(0, 1, 1) }.words[0];
+        assert_eq!(
+            alloc.kernel.mapping_attributes(res.vaddr()),
+            Some((read_write, DEFAULT_VM_ATTRIBUTES))
+        );
+    }
+
+    #[test]
+    fn page_tables_are_freed_when_the_page_still_cant_be_mapped() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+        alloc
+            .kernel
+            .set_page_map_error(seL4_Error_seL4_InvalidArgument);
+
+        assert_eq!(
+            alloc.vspace_new_ipc_buffer(),
+            Err(Error::Kernel(
+                Operation::PageMap,
+                KernelError::InvalidArgument
+            ))
+        );
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.num_paging_structures, 0);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    #[test]
+    fn the_mappings_table_can_be_replaced() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+
+        let first = new_page(&mut alloc);
+        let second = new_page(&mut alloc);
+
+        // Too small for the pages already mapped
+        let small = Box::leak(vec![MappedPage::unused(); 1].into_boxed_slice());
+        assert_eq!(
+            alloc.vspace_set_mappings_table(small),
+            Err(Error::MappingsExhausted)
+        );
+
+        let table = Box::leak(vec![MappedPage::unused(); 3].into_boxed_slice());
+        alloc.vspace_set_mappings_table(table).unwrap();
+        assert!(alloc.vspace_get_cap(first).is_some());
+        assert!(alloc.vspace_get_cap(second).is_some());
+
+        // The new table's size is the limit now
+        new_page(&mut alloc);
+        assert_eq!(
+            alloc.vspace_new_pages(
+                1,
+                seL4_PageBits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            ),
+            Err(Error::MappingsExhausted)
+        );
+
+        alloc
+            .vspace_free_pages(first, 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.vspace_get_cap(first), None);
+        assert!(alloc.vspace_get_cap(second).is_some());
+    }
+
+    #[test]
+    fn map_page_creates_every_paging_level() {
+        let mut alloc = sim::allocator_with_cnode_depth(&[(0x1000_0000, 20, false)], 20);
+        let num_caps = alloc.kernel.num_caps();
+
+        let vaddr = new_page(&mut alloc);
+        assert!(alloc.kernel.translate(vaddr).is_some());
+        assert_eq!(
+            alloc.kernel.cap_type(alloc.vspace_get_cap(vaddr).unwrap()),
+            Some(ObjectType::SmallPage.to_sel4())
+        );
+        assert_eq!(alloc.kernel.num_paging_structures(), PAGING_LEVELS.len());
+        assert_eq!(alloc.num_paging_structures, PAGING_LEVELS.len());
+
+        alloc
+            .vspace_free_pages(vaddr, 1, seL4_PageBits as _)
+            .unwrap();
+        assert!(alloc.kernel.translate(vaddr).is_none());
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.num_paging_structures, 0);
+        assert_eq!(alloc.num_slots_used, 0);
+        assert_eq!(alloc.kernel.num_caps(), num_caps);
+    }
+
+    #[test]
+    fn paging_structures_below_one_we_dont_own() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+        alloc.kernel.add_paging_structure(0, VSPACE_START);
+
+        let first = new_page(&mut alloc);
+        let second = new_page(&mut alloc);
+
+        // Ours are recorded at the levels the kernel mapped them at
+        assert_eq!(alloc.num_paging_structures, PAGING_LEVELS.len() - 1);
+        for level in 1..PAGING_LEVELS.len() {
+            assert!(alloc.find_paging_structure(level, first).is_some());
+        }
+
+        alloc
+            .vspace_free_pages(first, 1, seL4_PageBits as _)
+            .unwrap();
+        assert!(alloc.kernel.translate(second).is_some());
+
+        alloc
+            .vspace_free_pages(second, 1, seL4_PageBits as _)
+            .unwrap();
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert!(alloc.kernel.has_paging_structure(0, VSPACE_START));
+        assert_eq!(alloc.num_slots_used, 0);
+    }
+
+    /// Map a single frame of 2^'frame_bits', returning the number of
+    /// paging structures that had to be created for it
+    #[cfg(any(feature = "aarch64", feature = "x86_64"))]
+    fn paging_structures_for(frame_bits: u32) -> usize {
+        let mut alloc = sim::allocator(&[(0x4000_0000, 30, false), (0x8000_0000, 20, false)]);
+
+        let vaddr = alloc
+            .vspace_new_pages(
+                1,
+                frame_bits as _,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        let cap = alloc.vspace_get_cap(vaddr).unwrap();
+        assert_eq!(
+            alloc.kernel.cap_type(cap),
+            ObjectType::frame(frame_bits as _).ok().map(|t| t.to_sel4())
+        );
+        assert_eq!(
+            alloc.kernel.translate(vaddr + 0x10),
+            alloc.kernel.cap_paddr(cap).map(|paddr| paddr + 0x10)
+        );
+
+        alloc.kernel.num_paging_structures()
+    }
+
+    #[test]
+    #[cfg(all(feature = "aarch64", not(feature = "hyp")))]
+    fn aarch64_frames_are_mapped_at_their_level() {
+        // A page needs a PUD, PD and PT below the PGD, 2M frames go in the
+        // PD and 1G frames in the PUD
+        assert_eq!(paging_structures_for(seL4_PageBits), 3);
+        assert_eq!(paging_structures_for(seL4_LargePageBits), 2);
+        assert_eq!(paging_structures_for(seL4_HugePageBits), 1);
+    }
+
+    #[test]
+    #[cfg(all(feature = "aarch64", feature = "hyp"))]
+    fn aarch64_hyp_frames_are_mapped_below_a_pud_root() {
+        assert_eq!(paging_structures_for(seL4_PageBits), 2);
+        assert_eq!(paging_structures_for(seL4_LargePageBits), 1);
+        assert_eq!(paging_structures_for(seL4_HugePageBits), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "x86_64")]
+    fn x86_64_frames_are_mapped_at_their_level() {
+        // A page needs a PDPT, PD and PT below the PML4, 2M frames go in
+        // the PD and 1G frames in the PDPT
+        assert_eq!(paging_structures_for(seL4_PageBits), 3);
+        assert_eq!(paging_structures_for(seL4_LargePageBits), 2);
+        assert_eq!(paging_structures_for(seL4_HugePageBits), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "x86_64")]
+    fn x86_64_pages_above_the_canonical_hole_are_refused() {
+        let mut alloc = sim::allocator(&[(0x1000_0000, 20, false)]);
+
+        let res = alloc
+            .vspace_reserve_range_at(
+                1 << 47,
+                1 << seL4_PageBits,
+                unsafe { seL4_CapRights_new(1, 1, 1) },
+                DEFAULT_VM_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(
+            alloc.vspace_new_pages_at_vaddr(&res, res.vaddr(), 1, seL4_PageBits as _),
+            Err(Error::Kernel(
+                Operation::PageMap,
+                KernelError::InvalidArgument
+            ))
+        );
+        assert_eq!(alloc.kernel.num_paging_structures(), 0);
+        assert_eq!(alloc.num_slots_used, 0);
+    }
 }